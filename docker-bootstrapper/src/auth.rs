@@ -0,0 +1,238 @@
+use std::{collections::HashMap, ffi::OsStr, io, path::PathBuf, process::Stdio};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::auth::DockerCredentials;
+use color_eyre::eyre::{eyre, Error, WrapErr};
+use serde::Deserialize;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Key of Docker Hub in `auths` and for credential helpers
+const DOCKER_HUB: &str = "https://index.docker.io/v1/";
+
+/// Registry credentials of a Docker client config, usually
+/// `~/.docker/config.json`: `auths` entries, a `credsStore` and
+/// per-registry `credHelpers`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AuthEntry {
+    /// base64 of `username:password`
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// What a `docker-credential-*` helper prints for `get`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+impl DockerConfig {
+    /// `config.json` in `$DOCKER_CONFIG` or `~/.docker`, empty if there
+    /// is none
+    pub fn load() -> Result<Self, Error> {
+        let dir = match std::env::var_os("DOCKER_CONFIG") {
+            Some(dir) => PathBuf::from(dir),
+            None => match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".docker"),
+                None => return Ok(Self::default()),
+            },
+        };
+        let path = dir.join("config.json");
+        match std::fs::read_to_string(&path) {
+            Ok(json) => Self::parse(&json).wrap_err_with(|| format!("reading {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Credentials for `registry`, Docker Hub if `None`, from its
+    /// credential helper first and `auths` otherwise
+    pub async fn credentials(
+        &self,
+        registry: Option<&str>,
+    ) -> Result<Option<DockerCredentials>, Error> {
+        let host = host(registry.unwrap_or(DOCKER_HUB));
+        let server = match host {
+            "index.docker.io" => DOCKER_HUB,
+            _ => registry.unwrap_or(DOCKER_HUB),
+        };
+        let helper = self
+            .cred_helpers
+            .iter()
+            .find(|(x, _)| self::host(x) == host)
+            .map(|(_, helper)| helper)
+            .or(self.creds_store.as_ref());
+        if let Some(helper) = helper {
+            let program = format!("docker-credential-{}", helper);
+            if let Some(credentials) = run_helper(program, server).await? {
+                return Ok(Some(credentials));
+            }
+        }
+        let Some(entry) = self.auths.iter().find(|(x, _)| self::host(x) == host) else {
+            return Ok(None);
+        };
+        let (username, password) = match &entry.1.auth {
+            Some(auth) => {
+                let decoded = String::from_utf8(STANDARD.decode(auth)?)?;
+                let (username, password) = decoded
+                    .split_once(':')
+                    .ok_or_else(|| eyre!("invalid auth for {} in docker config", entry.0))?;
+                (Some(username.to_string()), Some(password.to_string()))
+            }
+            None => (entry.1.username.clone(), entry.1.password.clone()),
+        };
+        Ok(Some(DockerCredentials {
+            username,
+            password,
+            identitytoken: entry.1.identitytoken.clone(),
+            serveraddress: Some(server.to_string()),
+            ..Default::default()
+        }))
+    }
+}
+
+/// `get` the credentials of `server` from a credential helper, `None` if
+/// it has none
+async fn run_helper(
+    program: impl AsRef<OsStr>,
+    server: &str,
+) -> Result<Option<DockerCredentials>, Error> {
+    let program = program.as_ref();
+    let mut child = Command::new(program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err_with(|| format!("running credential helper {}", program.to_string_lossy()))?;
+    // closed once written, helpers read until the end
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(server.as_bytes()).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        return Err(eyre!(
+            "credential helper {} failed: {}",
+            program.to_string_lossy(),
+            format!("{} {}", message, String::from_utf8_lossy(&output.stderr)).trim()
+        ));
+    }
+    let helper: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+    // helpers store identity tokens with this username
+    let credentials = match helper.username.as_str() {
+        "<token>" => DockerCredentials {
+            identitytoken: Some(helper.secret),
+            ..Default::default()
+        },
+        _ => DockerCredentials {
+            username: Some(helper.username),
+            password: Some(helper.secret),
+            ..Default::default()
+        },
+    };
+    Ok(Some(DockerCredentials {
+        serveraddress: Some(server.to_string()),
+        ..credentials
+    }))
+}
+
+/// `https://registry:5000/v2/` as `registry:5000`, with Docker Hub's
+/// aliases as `index.docker.io`
+fn host(registry: &str) -> &str {
+    let registry = registry
+        .strip_prefix("https://")
+        .or_else(|| registry.strip_prefix("http://"))
+        .unwrap_or(registry);
+    match registry.split('/').next().unwrap_or_default() {
+        "docker.io" | "registry-1.docker.io" => "index.docker.io",
+        x => x,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::{run_helper, DockerConfig};
+
+    #[tokio::test]
+    async fn config_credentials() {
+        let config = DockerConfig::parse(
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": "dXNlcjpwYXNz" },
+                    "localhost:5000": { "username": "ci", "password": "secret" }
+                }
+            }"#,
+        )
+        .unwrap();
+        let hub = config.credentials(None).await.unwrap().unwrap();
+        assert_eq!(hub.username.as_deref(), Some("user"));
+        assert_eq!(hub.password.as_deref(), Some("pass"));
+        assert_eq!(
+            hub.serveraddress.as_deref(),
+            Some("https://index.docker.io/v1/")
+        );
+        let hub = config
+            .credentials(Some("docker.io"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(hub.username.as_deref(), Some("user"));
+
+        let local = config
+            .credentials(Some("localhost:5000"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(local.password.as_deref(), Some("secret"));
+        assert!(config.credentials(Some("ghcr.io")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn credential_helper() {
+        let dir = std::env::temp_dir().join(format!("docker-credential-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let helper = dir.join("docker-credential-stub");
+        fs::write(
+            &helper,
+            "#!/bin/sh\n\
+            read server\n\
+            case $server in\n\
+            registry:5000) echo '{\"ServerURL\":\"registry:5000\",\"Username\":\"<token>\",\"Secret\":\"t0k\"}';;\n\
+            *) echo 'credentials not found in native keychain'; exit 1;;\n\
+            esac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let found = run_helper(&helper, "registry:5000").await;
+        let missing = run_helper(&helper, "ghcr.io").await;
+        fs::remove_dir_all(&dir).unwrap();
+        let found = found.unwrap().unwrap();
+        assert_eq!(found.identitytoken.as_deref(), Some("t0k"));
+        assert_eq!(found.username, None);
+        assert!(missing.unwrap().is_none());
+    }
+}
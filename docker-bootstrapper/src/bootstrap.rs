@@ -1,10 +1,10 @@
-use std::{borrow::Cow, collections::HashMap, pin::Pin};
+use std::{collections::HashMap, pin::Pin};
 
 use bollard::Docker;
 use color_eyre::eyre::Error;
 use futures::{future::ready, Future, FutureExt};
 
-use crate::{ContainerBuilder, ContainerNetworkBuilder};
+use crate::{ContainerBuilder, ContainerNetworkBuilder, DockerFileSource};
 
 pub struct ContainerFut<'a, T, O = ()> {
     fut: Pin<Box<dyn Future<Output = O>>>,
@@ -81,7 +81,7 @@ impl<'a, T> BootstrapDockerNet<'a, T> {
     ) -> Result<(), Error>
     where
         E: std::error::Error + Send + Sync + 'static,
-        T: Into<Cow<'b, str>>,
+        T: DockerFileSource<'b>,
     {
         match Runner::from_env() {
             Runner::ContainerId(id) => self.container_futs.remove(&id).unwrap().fut.await,
@@ -92,7 +92,7 @@ impl<'a, T> BootstrapDockerNet<'a, T> {
 
     async fn master_run<'b>(self, docker: &Docker) -> Result<(), Error>
     where
-        T: Into<Cow<'b, str>>,
+        T: DockerFileSource<'b>,
     {
         let net_builder = ContainerNetworkBuilder::new(self.name);
         let containers = self.container_futs.into_iter().map(|(id, c)| {
@@ -1,16 +1,17 @@
 use std::{
-    borrow::Cow,
     env::{self},
     fmt::Display,
     path::Path,
 };
 
 use bollard::{
-    container::{
-        Config, CreateContainerOptions, LogOutput, LogsOptions, RemoveContainerOptions,
+    container::LogOutput,
+    exec::{CreateExecOptions, StartExecResults},
+    models::ContainerCreateBody,
+    query_parameters::{
+        CreateContainerOptions, LogsOptions, RemoveContainerOptions, StartContainerOptions,
         WaitContainerOptions,
     },
-    exec::{CreateExecOptions, StartExecResults},
     Docker,
 };
 use color_eyre::eyre::{eyre, Error};
@@ -19,31 +20,61 @@ use color_eyre::owo_colors::OwoColorize;
 
 use futures::{Stream, TryStreamExt};
 
-use crate::ImageBuilder;
+use crate::{BuildEvent, DockerFileSource, ImageBuilder, ImagePuller, OciImageBuilder};
+
+impl<'a, T: Clone> ImageBuilder<T> {
+    pub fn to_container(&self, name: &'a str) -> ContainerBuilder<'a, T> {
+        ContainerBuilder::new(name, self.clone())
+    }
+}
+
+impl ImagePuller {
+    pub fn to_container<'a, T>(&self, name: &'a str) -> ContainerBuilder<'a, T> {
+        ContainerBuilder::pulled(name, self.clone())
+    }
+}
 
-impl<'a, T> ImageBuilder<T> {
-    pub fn to_container(self, name: &'a str) -> ContainerBuilder<T> {
-        ContainerBuilder::new(name, self)
+impl OciImageBuilder {
+    pub fn to_container<'a, T>(&self, name: &'a str) -> ContainerBuilder<'a, T> {
+        ContainerBuilder::assembled(name, self.clone())
     }
 }
 
+/// Where the image of a container comes from
+enum ContainerImage<T> {
+    Build(ImageBuilder<T>),
+    Pull(ImagePuller),
+    Assemble(OciImageBuilder),
+}
+
 pub struct ContainerBuilder<'a, T> {
-    image: ImageBuilder<T>,
-    opts: CreateContainerOptions<&'a str>,
-    config: Config<String>,
+    image: ContainerImage<T>,
+    name: &'a str,
+    config: ContainerCreateBody,
     /// If is waited for the docker network before it removes this container with it finishing its execution
     is_waited: bool,
 }
 
 impl<'a, T> ContainerBuilder<'a, T> {
     pub fn new(name: &'a str, image_builder: ImageBuilder<T>) -> Self {
+        Self::with_image(name, ContainerImage::Build(image_builder))
+    }
+
+    /// A container of a pulled image instead of a built one
+    pub fn pulled(name: &'a str, image_puller: ImagePuller) -> Self {
+        Self::with_image(name, ContainerImage::Pull(image_puller))
+    }
+
+    /// A container of an image assembled without a build
+    pub fn assembled(name: &'a str, image_builder: OciImageBuilder) -> Self {
+        Self::with_image(name, ContainerImage::Assemble(image_builder))
+    }
+
+    fn with_image(name: &'a str, image: ContainerImage<T>) -> Self {
         Self {
-            opts: CreateContainerOptions {
-                name,
-                ..Default::default()
-            },
-            image: image_builder,
-            config: Config {
+            name,
+            image,
+            config: ContainerCreateBody {
                 image: None,
                 tty: Some(true),
                 attach_stdout: Some(true),
@@ -110,14 +141,42 @@ impl<'a, T> ContainerBuilder<'a, T> {
             .with_cmd([exe.to_string_lossy().into_owned()].into_iter().chain(args))
     }
 
-    pub async fn build<'b>(mut self, docker: &Docker) -> Result<Container, Error>
+    pub(crate) fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub async fn build<'b>(self, docker: &Docker) -> Result<Container, Error>
     where
-        T: Into<Cow<'b, str>>,
+        T: DockerFileSource<'b>,
     {
-        let name = self.opts.name.to_string();
-        self.config.image = Some(self.image.build(docker).await?.id);
+        self.build_with(docker, |image, event| event.trace(image))
+            .await
+    }
+
+    /// [Self::build], handing the image's build events to `on_event`
+    pub(crate) async fn build_with<'b>(
+        mut self,
+        docker: &Docker,
+        on_event: impl FnMut(&str, &BuildEvent),
+    ) -> Result<Container, Error>
+    where
+        T: DockerFileSource<'b>,
+    {
+        let name = self.name.to_string();
+        let image = match self.image {
+            ContainerImage::Build(builder) => builder.build_with(docker, on_event).await?,
+            ContainerImage::Pull(puller) => puller.pull(docker).await?,
+            ContainerImage::Assemble(builder) => builder.build(docker).await?,
+        };
+        self.config.image = Some(image.id);
         let info = docker
-            .create_container(Some(self.opts), self.config)
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: Some(name.clone()),
+                    ..Default::default()
+                }),
+                self.config,
+            )
             .await?;
         Ok(Container::new(info.id, name, self.is_waited))
     }
@@ -148,7 +207,9 @@ impl Container {
     }
 
     pub async fn start(&self, docker: &Docker) -> Result<(), Error> {
-        docker.start_container::<String>(&self.id, None).await?;
+        docker
+            .start_container(&self.id, None::<StartContainerOptions>)
+            .await?;
         Ok(())
     }
 
@@ -166,7 +227,7 @@ impl Container {
     }
 
     pub async fn run(&self, docker: &Docker) -> Result<(), Error> {
-        let logs = docker.logs::<String>(
+        let logs = docker.logs(
             &self.id,
             Some(LogsOptions {
                 follow: true,
@@ -197,7 +258,7 @@ impl Container {
         follow: bool,
     ) -> impl Stream<Item = Result<LogOutput, Error>> {
         docker
-            .logs::<String>(
+            .logs(
                 &self.id,
                 Some(LogsOptions {
                     follow,
@@ -215,7 +276,7 @@ impl Container {
             .wait_container(
                 &self.name,
                 Some(WaitContainerOptions {
-                    condition: "not-running",
+                    condition: "not-running".to_string(),
                 }),
             )
             .map_err(|e| match e {
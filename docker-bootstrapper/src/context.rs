@@ -0,0 +1,366 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use futures::{channel::mpsc, executor::block_on, SinkExt, Stream};
+use sha2::{Digest, Sha256};
+
+/// Bytes of tar gathered before they are sent to the daemon
+const CHUNK: usize = 1 << 16;
+
+/// Files sent to the daemon for a build: a directory on disk, files
+/// generated in memory, and the Dockerfile itself
+#[derive(Debug, Clone, Default)]
+pub struct BuildContext {
+    dir: Option<PathBuf>,
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl BuildContext {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Send the files of `dir` except those matched by its `.dockerignore`,
+    /// keeping their modes and symlinks
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// A file at `path` in the context, in place of one in the directory
+    ///
+    /// # Panics
+    /// If `path` is empty, goes outside the context with `..`, or is
+    /// `Dockerfile`, which is always the Dockerfile being built
+    #[track_caller]
+    pub fn with_file(mut self, path: impl AsRef<str>, contents: impl Into<Vec<u8>>) -> Self {
+        let path = clean(path.as_ref());
+        assert!(
+            path.as_ref().is_some_and(|x| !x.is_empty()),
+            "invalid path in build context: {}",
+            path.unwrap_or_default()
+        );
+        let path = path.unwrap();
+        assert!(
+            path != "Dockerfile",
+            "the Dockerfile of a build context is the one being built"
+        );
+        self.files.insert(path, contents.into());
+        self
+    }
+
+    /// Write the context as a gzipped tar to `out` with `dockerfile` as
+    /// `Dockerfile`, one file at a time, returning the digest of the
+    /// (uncompressed) tar
+    ///
+    /// The same files always make the same bytes: entries are sorted,
+    /// owned by root and dated to `$SOURCE_DATE_EPOCH` (or the epoch),
+    /// as is the gzip header
+    pub fn write_tar(&self, dockerfile: &str, out: impl Write) -> io::Result<String> {
+        let gz = flate2::GzBuilder::new()
+            .mtime(source_date_epoch() as u32)
+            .operating_system(UNKNOWN_OS)
+            .write(out, flate2::Compression::new(6));
+        let (gz, digest) = self.append_all(dockerfile, gz)?;
+        gz.finish()?;
+        Ok(digest)
+    }
+
+    /// Digest [Self::write_tar] returns, reading the files without
+    /// compressing or keeping the tar
+    pub fn digest(&self, dockerfile: &str) -> io::Result<String> {
+        Ok(self.append_all(dockerfile, io::sink())?.1)
+    }
+
+    /// The tar of [Self::write_tar], written on a blocking thread as the
+    /// daemon reads it. A failure ends the stream with its error
+    pub(crate) fn tar_stream(self, dockerfile: String) -> impl Stream<Item = io::Result<Bytes>> {
+        let (mut tx, rx) = mpsc::channel(1);
+        let out = ChannelWriter(tx.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut out = io::BufWriter::with_capacity(CHUNK, out);
+            let written = self
+                .write_tar(&dockerfile, &mut out)
+                .and_then(|_| out.flush());
+            if let Err(e) = written {
+                let _ = block_on(tx.send(Err(e)));
+            }
+        });
+        rx
+    }
+
+    /// Append the files to a tar written to `out`, returning `out` and
+    /// the digest of the tar
+    fn append_all<W: Write>(&self, dockerfile: &str, out: W) -> io::Result<(W, String)> {
+        let mtime = source_date_epoch();
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            "Dockerfile".to_string(),
+            Entry::Bytes(dockerfile.as_bytes(), 0o755),
+        );
+        if let Some(dir) = &self.dir {
+            let ignore = DockerIgnore::read(dir)?;
+            self.list_dir(&mut entries, dir, "", &ignore)?;
+        }
+        for (path, contents) in &self.files {
+            entries.insert(path.clone(), Entry::Bytes(contents, 0o644));
+        }
+
+        let mut tar = tar::Builder::new(HashWriter {
+            inner: out,
+            hasher: Sha256::new(),
+        });
+        for (path, entry) in &entries {
+            entry.append(&mut tar, path, mtime)?;
+        }
+        let hashed = tar.into_inner()?;
+        let digest = format!("sha256:{}", hex::encode(hashed.hasher.finalize()));
+        Ok((hashed.inner, digest))
+    }
+
+    fn list_dir<'a>(
+        &self,
+        entries: &mut BTreeMap<String, Entry<'a>>,
+        dir: &Path,
+        prefix: &str,
+        ignore: &DockerIgnore,
+    ) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            // the Dockerfile being built replaces one in the directory
+            if path == "Dockerfile" || self.files.contains_key(&path) {
+                continue;
+            }
+            let excluded = ignore.is_excluded(&path);
+            if !excluded {
+                entries.insert(path.clone(), Entry::Disk(entry.path()));
+            }
+            // an excluded directory may still hold `!exceptions`
+            let is_dir = entry.file_type()?.is_dir();
+            if is_dir && (!excluded || ignore.has_exceptions()) {
+                self.list_dir(entries, &entry.path(), &format!("{}/", path), ignore)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Value of the gzip header's OS field meaning unknown, rather than the
+/// OS writing it
+const UNKNOWN_OS: u8 = 255;
+
+/// `$SOURCE_DATE_EPOCH`, the time reproducible builds date files to
+fn source_date_epoch() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// A file of the context
+enum Entry<'a> {
+    Bytes(&'a [u8], u32),
+    /// Read when written, keeping its mode, or link
+    Disk(PathBuf),
+}
+
+impl Entry<'_> {
+    fn append(&self, tar: &mut tar::Builder<impl Write>, path: &str, mtime: u64) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        match self {
+            Entry::Bytes(contents, mode) => {
+                header.set_size(contents.len() as u64);
+                header.set_mode(*mode);
+                tar.append_data(&mut header, path, *contents)
+            }
+            Entry::Disk(disk) => {
+                let metadata = fs::symlink_metadata(disk)?;
+                header.set_mode(metadata.permissions().mode() & 0o7777);
+                let file_type = metadata.file_type();
+                if file_type.is_symlink() {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    tar.append_link(&mut header, path, fs::read_link(disk)?)
+                } else if file_type.is_dir() {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    tar.append_data(&mut header, path, io::empty())
+                } else {
+                    header.set_size(metadata.len());
+                    tar.append_data(&mut header, path, fs::File::open(disk)?)
+                }
+            }
+        }
+    }
+}
+
+/// Hashes what goes through it
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sends what is written to it down a channel, failing once the
+/// receiver is dropped, e.g. by an aborted build
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on(self.0.send(Ok(Bytes::copy_from_slice(buf))))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `path` relative to the context root with `.`, `..` and duplicate
+/// slashes resolved, `None` if it leaves the context
+fn clean(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            x => segments.push(x),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+/// Patterns of a `.dockerignore`, following Docker's rules: `*`, `?` and
+/// `[...]` match within a path segment, `**` any number of segments, a
+/// pattern matching a directory matches everything in it, and `!` makes
+/// an exception. The last pattern matching a path decides
+#[derive(Debug, Clone, Default)]
+pub struct DockerIgnore {
+    /// Whether the pattern excludes (or is an exception), with its segments
+    patterns: Vec<(bool, Vec<String>)>,
+}
+
+impl DockerIgnore {
+    pub fn parse(text: &str) -> Self {
+        let patterns = text
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .filter_map(|line| {
+                let (exclude, pattern) = match line.strip_prefix('!') {
+                    Some(x) => (false, x.trim()),
+                    None => (true, line),
+                };
+                // a pattern going above the root can't match anything
+                let pattern = clean(pattern).filter(|x| !x.is_empty())?;
+                let segments = pattern.split('/').map(|x| x.to_string()).collect();
+                Some((exclude, segments))
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    /// The `.dockerignore` in `dir`, empty if there is none
+    pub fn read(dir: &Path) -> io::Result<Self> {
+        match fs::read_to_string(dir.join(".dockerignore")) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `path`, relative to the context root, is left out
+    pub fn is_excluded(&self, path: &str) -> bool {
+        let path: Vec<_> = path.split('/').filter(|x| !x.is_empty()).collect();
+        let mut excluded = false;
+        for (exclude, pattern) in &self.patterns {
+            if (1..=path.len()).any(|i| matches(pattern, &path[..i])) {
+                excluded = *exclude;
+            }
+        }
+        excluded
+    }
+
+    pub fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|(exclude, _)| !exclude)
+    }
+}
+
+fn matches(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((x, rest)) if x == "**" => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+        Some((x, rest)) => path.split_first().is_some_and(|(segment, path)| {
+            let x: Vec<_> = x.chars().collect();
+            let segment: Vec<_> = segment.chars().collect();
+            glob(&x, &segment) && matches(rest, path)
+        }),
+    }
+}
+
+/// Shell style matching of a single path segment
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', rest @ ..] => (0..=name.len()).any(|i| glob(rest, &name[i..])),
+        ['?', rest @ ..] => !name.is_empty() && glob(rest, &name[1..]),
+        ['[', rest @ ..] => {
+            let (negate, class) = match rest {
+                ['^' | '!', class @ ..] => (true, class),
+                class => (false, class),
+            };
+            // a `]` right after `[` or `[^` is part of the class
+            match class.iter().skip(1).position(|x| *x == ']') {
+                Some(end) => name.split_first().is_some_and(|(c, name)| {
+                    in_class(&class[..end + 1], *c) != negate && glob(&class[end + 2..], name)
+                }),
+                None => name.first() == Some(&'[') && glob(rest, &name[1..]),
+            }
+        }
+        ['\\', x, rest @ ..] => name.first() == Some(x) && glob(rest, &name[1..]),
+        [x, rest @ ..] => name.first() == Some(x) && glob(rest, &name[1..]),
+    }
+}
+
+/// Whether `c` is in a class such as `a-z_`
+fn in_class(class: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            if (class[i]..=class[i + 2]).contains(&c) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
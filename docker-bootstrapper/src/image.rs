@@ -1,67 +1,712 @@
-use color_eyre::eyre::Error;
-use std::{borrow::Cow, io::Write};
+use color_eyre::eyre::{eyre, Error};
+use dockerfiles::{Copy, DockerFile, ImageRef, Mount, Run};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    panic::Location,
+    path::PathBuf,
+    pin::pin,
+    sync::{Arc, LazyLock, Mutex},
+};
 
-use bollard::{image::BuildImageOptions, Docker};
-use futures::{future::ready, TryStreamExt};
+use bollard::{
+    models::BuildInfo,
+    query_parameters::{BuildImageOptions, BuilderVersion, ListImagesOptions, TagImageOptions},
+    Docker,
+};
+use futures::{future::ready, stream, Stream, StreamExt, TryStreamExt};
+use rand::{distributions::Alphanumeric, Rng};
 
-#[derive(Clone, Copy)]
+use crate::BuildContext;
+
+mod cache;
+mod error;
+mod event;
+mod inspect;
+mod oci;
+mod pull;
+mod push;
+
+pub use cache::ImageCache;
+pub use error::BuildError;
+use error::{Layout, StepTracker};
+pub use event::BuildEvent;
+use event::EventParser;
+pub use inspect::{ImageMetadata, LayerHistory};
+pub use oci::OciImageBuilder;
+pub use pull::*;
+
+/// Frontend used when BuildKit-only options are found and the
+/// Dockerfile doesn't pick one itself
+const BUILDKIT_SYNTAX: &str = "docker/dockerfile:1";
+
+/// Frontend used instead of [BUILDKIT_SYNTAX] for `COPY --parents`,
+/// which isn't in the stable one yet
+const BUILDKIT_LABS_SYNTAX: &str = "docker/dockerfile:1.7-labs";
+
+/// Label holding the hash of everything an image was built from, so an
+/// identical build can reuse it
+pub const CONTENT_HASH_LABEL: &str = "docker-bootstrapper.content-hash";
+
+/// One lock per content hash, so concurrent builds of the same image in
+/// this process wait for the first one and then reuse its image
+static BUILDS: LazyLock<Mutex<HashMap<String, Arc<futures::lock::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// A Dockerfile [ImageBuilder] can build: its text, or a [DockerFile]
+/// which also knows where its instructions were added, for [BuildError]
+pub trait DockerFileSource<'a> {
+    fn into_text(self) -> Cow<'a, str>;
+
+    /// Instructions and where they were added, see [DockerFile::locations]
+    fn locations(&self) -> Vec<((usize, usize), String, &'static Location<'static>)> {
+        Vec::new()
+    }
+}
+
+impl<'a> DockerFileSource<'a> for &'a DockerFile {
+    fn into_text(self) -> Cow<'a, str> {
+        self.into()
+    }
+
+    fn locations(&self) -> Vec<((usize, usize), String, &'static Location<'static>)> {
+        DockerFile::locations(self)
+            .map(|(position, x, location)| (position, x.to_string(), location))
+            .collect()
+    }
+}
+
+impl<'a> DockerFileSource<'a> for &'a str {
+    fn into_text(self) -> Cow<'a, str> {
+        self.into()
+    }
+}
+
+impl<'a> DockerFileSource<'a> for &'a String {
+    fn into_text(self) -> Cow<'a, str> {
+        self.into()
+    }
+}
+
+impl<'a> DockerFileSource<'a> for String {
+    fn into_text(self) -> Cow<'a, str> {
+        self.into()
+    }
+}
+
+impl<'a> DockerFileSource<'a> for Cow<'a, str> {
+    fn into_text(self) -> Cow<'a, str> {
+        self
+    }
+}
+
+#[derive(Clone)]
 pub struct ImageBuilder<T> {
     docker_file: T,
+    context: BuildContext,
+    target: Option<String>,
+    tag: Option<ImageRef>,
+    build_args: HashMap<String, String>,
+    labels: HashMap<String, String>,
+    no_cache: bool,
+    pull: bool,
+    rm: bool,
+    force_rm: bool,
+    network_mode: Option<String>,
+    shm_size: Option<u64>,
+    extra_host: Option<String>,
+    reuse: bool,
+    cache: Option<ImageCache>,
+    validate: bool,
+    optimize: bool,
 }
 
 impl<T> ImageBuilder<T> {
     pub fn new(docker_file: T) -> Self {
-        Self { docker_file }
+        Self {
+            docker_file,
+            context: Default::default(),
+            target: Default::default(),
+            tag: Default::default(),
+            build_args: Default::default(),
+            labels: Default::default(),
+            no_cache: false,
+            pull: false,
+            rm: true,
+            force_rm: false,
+            network_mode: Default::default(),
+            shm_size: Default::default(),
+            extra_host: None,
+            reuse: true,
+            cache: Default::default(),
+            validate: false,
+            optimize: false,
+        }
+    }
+
+    /// Send the files of `dir` with the Dockerfile, so `COPY` can use
+    /// them, see [BuildContext::with_dir]
+    pub fn with_context_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.context = self.context.with_dir(dir);
+        self
+    }
+
+    /// A file generated in memory at `path` in the build context
+    ///
+    /// # Panics
+    /// If `path` is empty, goes outside the context with `..`, or is
+    /// `Dockerfile`, see [BuildContext::with_file]
+    #[track_caller]
+    pub fn with_file(mut self, path: impl AsRef<str>, contents: impl Into<Vec<u8>>) -> Self {
+        self.context = self.context.with_file(path, contents);
+        self
+    }
+
+    /// Whether to use an image built earlier from the same Dockerfile,
+    /// context, options and base images instead of building again, `true`
+    /// by default. Never done with [Self::with_no_cache] or [Self::with_pull]
+    pub fn with_reuse(mut self, reuse: bool) -> Self {
+        self.reuse = reuse;
+        self
+    }
+
+    /// Load the image from `dir` instead of building it if an identical
+    /// build was saved there, and save it there once built. Base images
+    /// missing from the daemon are loaded from it too, see [ImageCache]
+    pub fn with_image_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache = Some(ImageCache::new(dir));
+        self
+    }
+
+    /// Whether to lint the Dockerfile before building, refusing to
+    /// build it if there are errors, see [DockerFile::lint]
+    pub fn with_validation(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Whether to merge layers and reorder package installs before
+    /// building, logging what changed, see [DockerFile::optimize]
+    pub fn with_optimization(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Tag the built image, e.g. `localhost:5000/app:test`
+    pub fn with_tag(mut self, tag: ImageRef) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Stop a multi-stage build at the stage with this alias
+    pub fn with_target(mut self, stage: impl ToString) -> Self {
+        self.target = Some(stage.to_string());
+        self
+    }
+
+    /// Value of an `ARG`, so one Dockerfile can be built into variants
+    pub fn with_build_arg(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.build_args.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Label the built image, like a `LABEL` at the end of the Dockerfile
+    pub fn with_label(mut self, key: impl ToString, value: impl ToString) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Whether to build every step again instead of using cached layers
+    pub fn with_no_cache(mut self, no_cache: bool) -> Self {
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Whether to pull newer versions of the `FROM` images
+    pub fn with_pull(mut self, pull: bool) -> Self {
+        self.pull = pull;
+        self
+    }
+
+    /// Whether to remove intermediate containers after a successful
+    /// build, `true` by default like `docker build`
+    pub fn with_rm(mut self, rm: bool) -> Self {
+        self.rm = rm;
+        self
+    }
+
+    /// Whether to remove intermediate containers even when the build fails
+    pub fn with_force_rm(mut self, force_rm: bool) -> Self {
+        self.force_rm = force_rm;
+        self
+    }
+
+    /// Network `RUN`s use, e.g. `host`, `none` or a network's name
+    pub fn with_network_mode(mut self, network_mode: impl ToString) -> Self {
+        self.network_mode = Some(network_mode.to_string());
+        self
+    }
+
+    /// Size of `/dev/shm` in bytes, under 2GiB
+    pub fn with_shm_size(mut self, bytes: u64) -> Self {
+        self.shm_size = Some(bytes);
+        self
+    }
+
+    /// Add `host` to `/etc/hosts` during the build. The daemon takes a
+    /// single extra host, so this replaces the one set before
+    pub fn with_extra_host(mut self, host: impl ToString, ip: impl ToString) -> Self {
+        self.extra_host = Some(format!("{}:{}", host.to_string(), ip.to_string()));
+        self
     }
 
     pub async fn build<'a>(self, docker: &Docker) -> Result<Image, Error>
     where
-        T: Into<Cow<'a, str>>,
+        T: DockerFileSource<'a>,
     {
-        let opts = BuildImageOptions {
-            dockerfile: "Dockerfile",
-            ..Default::default()
-        };
+        self.build_with(docker, |image, event| event.trace(image))
+            .await
+    }
 
-        let tar = self.create_docker_tarball().into();
-        let images = docker.build_image(opts, None, Some(tar));
-        let infos = images
-            .inspect_ok(|x| {
-                // TODO: use tracing
-                x.stream.as_ref().inspect(|x| print!("{}", x));
-            })
-            .try_filter_map(|x| ready(Ok(x.aux)));
+    /// Build, yielding what happens as it does, the last event being the
+    /// [BuildEvent::Image] unless the build fails
+    pub fn build_stream<'a>(
+        self,
+        docker: &Docker,
+    ) -> impl Stream<Item = Result<BuildEvent, Error>> + '_
+    where
+        T: DockerFileSource<'a>,
+    {
+        match self.request() {
+            Ok(request) => request.send(docker).left_stream(),
+            Err(e) => stream::once(ready(Err(e))).right_stream(),
+        }
+    }
 
-        // TODO: stop using vec
-        let id: Vec<_> = infos.try_collect().await?;
-        let id = id
-            .into_iter()
-            .next()
-            .unwrap()
-            .id
-            .expect("image built without id");
+    /// [Self::build], handing every event to `on_event` along with the
+    /// name of the image
+    pub(crate) async fn build_with<'a>(
+        self,
+        docker: &Docker,
+        mut on_event: impl FnMut(&str, &BuildEvent),
+    ) -> Result<Image, Error>
+    where
+        T: DockerFileSource<'a>,
+    {
+        let request = self.request()?;
+        let name = request.name.clone();
+        let mut tracker = StepTracker::new(
+            name.clone(),
+            request.layout.clone(),
+            request.locations.clone(),
+        );
+        let mut events = pin!(request.send(docker));
+        let mut id = None;
+        while let Some(event) = events.try_next().await? {
+            on_event(&name, &event);
+            match event {
+                BuildEvent::Error {
+                    vertex,
+                    instruction,
+                    message,
+                } => {
+                    let error = tracker.error(vertex.as_deref(), instruction.as_deref(), &message);
+                    return Err(error.into());
+                }
+                BuildEvent::Image(x) => id = Some(x),
+                _ => tracker.track(&event),
+            }
+        }
+        let id = id.ok_or_else(|| tracker.error(None, None, "the daemon sent no image id"))?;
         Ok(Image::new(id))
     }
 
-    fn create_docker_tarball<'a>(self) -> Vec<u8>
+    /// Digest of the context sent to the daemon, the Dockerfile included,
+    /// the same for the same files wherever they are built (see
+    /// [BuildContext::write_tar]) so it can key caches
+    pub fn context_digest<'a>(&self) -> Result<String, Error>
+    where
+        T: DockerFileSource<'a> + Clone,
+    {
+        Ok(self.clone().request()?.context_digest)
+    }
+
+    /// Everything sent to the daemon for this build
+    fn request<'a>(self) -> Result<BuildRequest, Error>
     where
-        T: Into<Cow<'a, str>>,
+        T: DockerFileSource<'a>,
     {
-        let mut header = tar::Header::new_gnu();
-        let dockerfile: Cow<_> = self.docker_file.into();
+        let mut opts = self.options()?;
 
-        header.set_path("Dockerfile").unwrap();
-        header.set_size(dockerfile.len() as u64);
-        header.set_mode(0o755);
-        header.set_cksum();
-        let mut tar = tar::Builder::new(Vec::new());
-        tar.append(&header, dockerfile.as_bytes()).unwrap();
+        let locations = self.docker_file.locations();
+        let mut dockerfile = self.docker_file.into_text();
+        if self.validate {
+            Self::validate(&dockerfile)?;
+        }
+        if self.optimize {
+            dockerfile = Self::optimize(&dockerfile)?.into();
+        }
+        // the daemon may pull newer base images than the local ones
+        let mut reuse = self.reuse && !self.no_cache && !self.pull;
+        let parsed = DockerFile::parse(&dockerfile).ok();
+        let base_images = parsed
+            .as_ref()
+            .and_then(|x| Self::base_images(x, &self.build_args));
+        // without the base images, or with a `$` in their names, the hash
+        // would reuse the image built from whatever they were the first time
+        if base_images.is_none() {
+            reuse = false;
+        }
+        let layout = parsed.as_ref().map(Layout::new).unwrap_or_default();
+        if let Some(parsed) = parsed {
+            Self::check_secrets(&parsed)?;
+            // the legacy builder rejects RUN --mount and friends
+            if parsed.needs_buildkit() {
+                if parsed.directive("syntax").is_none() {
+                    let syntax = Self::buildkit_syntax(&parsed);
+                    dockerfile = format!("# syntax={}\n{}", syntax, dockerfile).into();
+                }
+                opts.version = BuilderVersion::BuilderBuildKit;
+                opts.session = Some(Self::session_id());
+            }
+        }
 
-        let uncompressed = tar.into_inner().unwrap();
-        let mut c = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
-        c.write_all(&uncompressed).unwrap();
-        c.finish().unwrap()
+        // the tar is only written when sent, see [BuildRequest::send]
+        let context_digest = self.context.digest(&dockerfile)?;
+        let name = match &self.tag {
+            Some(tag) => tag.to_string(),
+            None => format!(
+                "image {}",
+                &context_digest.trim_start_matches("sha256:")[..12]
+            ),
+        };
+        Ok(BuildRequest {
+            name,
+            context_digest,
+            locations,
+            layout,
+            opts,
+            context: self.context,
+            dockerfile: dockerfile.into_owned(),
+            tag: self.tag,
+            reuse,
+            cache: self.cache,
+            base_images: base_images.unwrap_or_default(),
+        })
+    }
+
+    fn options(&self) -> Result<BuildImageOptions, Error> {
+        let shm_size = self
+            .shm_size
+            .map(|x| {
+                i32::try_from(x).map_err(|_| eyre!("shm size {} is over the 2GiB bollard sends", x))
+            })
+            .transpose()?;
+        Ok(BuildImageOptions {
+            dockerfile: "Dockerfile".to_string(),
+            target: self.target.clone().unwrap_or_default(),
+            t: self.tag.as_ref().map(|x| x.to_string()),
+            buildargs: Some(self.build_args.clone()),
+            labels: Some(self.labels.clone()),
+            nocache: self.no_cache,
+            pull: self.pull.then(|| "true".to_string()),
+            rm: self.rm,
+            forcerm: self.force_rm,
+            networkmode: self.network_mode.clone(),
+            shmsize: shm_size,
+            extrahosts: self.extra_host.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// BuildKit needs a session for the build to attach to. bollard's
+    /// session only serves registry credentials, so `--mount=type=secret`
+    /// is left empty, see [Self::check_secrets]
+    fn session_id() -> String {
+        let id: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(25)
+            .map(char::from)
+            .collect();
+        format!("docker-bootstrapper-{}", id)
+    }
+
+    /// The images the stages start from, with the global `ARG`s in their
+    /// names substituted. `None` if one of them can't be, e.g. an `ARG`
+    /// without a value
+    fn base_images(
+        dockerfile: &DockerFile,
+        build_args: &HashMap<String, String>,
+    ) -> Option<Vec<String>> {
+        let args: HashMap<_, _> = dockerfile
+            .global_args()
+            .iter()
+            .filter_map(|arg| {
+                let value = build_args.get(&arg.name).or(arg.default.as_ref())?;
+                Some((arg.name.as_str(), value.as_str()))
+            })
+            .collect();
+        let mut aliases = Vec::new();
+        let mut images = Vec::new();
+        for stage in dockerfile.stages() {
+            let name = stage.from().image_ref().to_string();
+            if !aliases.contains(&name) && name != "scratch" {
+                let image = substitute_args(&name, &args)?.parse().ok()?;
+                images.push(pull::daemon_reference(&image));
+            }
+            aliases.extend(stage.from().alias().map(str::to_string));
+        }
+        Some(images)
+    }
+
+    /// Frontend for a Dockerfile without a `syntax` directive
+    fn buildkit_syntax(dockerfile: &DockerFile) -> &'static str {
+        match dockerfile.instructions_of::<Copy>().any(|x| x.parents) {
+            true => BUILDKIT_LABS_SYNTAX,
+            false => BUILDKIT_SYNTAX,
+        }
+    }
+
+    /// Refuse `required` secret mounts up front: the build session has no
+    /// secrets to serve, so BuildKit would fail them halfway through
+    fn check_secrets(dockerfile: &DockerFile) -> Result<(), Error> {
+        let required = dockerfile
+            .instructions_of::<Run>()
+            .flat_map(|x| &x.mounts)
+            .find_map(|x| match x {
+                Mount::Secret { id, required, .. } if *required => Some(id),
+                _ => None,
+            });
+        match required {
+            Some(id) => Err(eyre!(
+                "secret {} is required, but builds can't be passed secrets",
+                id
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn validate(dockerfile: &str) -> Result<(), Error> {
+        let diagnostics = DockerFile::parse(dockerfile)?.validate().map_err(|x| {
+            eyre!(
+                "refusing to build invalid Dockerfile:\n{}",
+                x.iter().join("\n")
+            )
+        })?;
+        diagnostics.iter().for_each(|x| tracing::warn!("{}", x));
+        Ok(())
+    }
+
+    fn optimize(dockerfile: &str) -> Result<String, Error> {
+        let mut parsed = DockerFile::parse(dockerfile)?;
+        let changes = parsed.optimize();
+        changes.iter().for_each(|x| tracing::info!("{}", x));
+        Ok(parsed.to_string())
+    }
+}
+
+struct BuildRequest {
+    /// The tag, or a short context digest, to tell builds apart in logs
+    name: String,
+    context_digest: String,
+    locations: Vec<((usize, usize), String, &'static Location<'static>)>,
+    layout: Layout,
+    opts: BuildImageOptions,
+    /// Written into the tar as it is sent
+    context: BuildContext,
+    dockerfile: String,
+    tag: Option<ImageRef>,
+    reuse: bool,
+    cache: Option<ImageCache>,
+    /// Loaded from the cache if missing, and hashed by id
+    base_images: Vec<String>,
+}
+
+impl BuildRequest {
+    fn send(mut self, docker: &Docker) -> impl Stream<Item = Result<BuildEvent, Error>> + '_ {
+        stream::once(async move {
+            if let Some(cache) = &self.cache {
+                for image in &self.base_images {
+                    if Image::local(docker, image).await?.is_none() {
+                        cache.load(docker, image).await?;
+                    }
+                }
+            }
+            // a base image the daemon has yet to pull is hashed by name,
+            // so the next build, which has its id, builds again once
+            let mut base_ids = Vec::new();
+            for image in &self.base_images {
+                let local = Image::local(docker, image).await?;
+                base_ids.push(local.map_or_else(|| image.clone(), |x| x.id));
+            }
+            // images built by daemons of other platforms may share a cache
+            let platform = match self.opts.platform.as_str() {
+                "" => {
+                    let version = docker.version().await?;
+                    let (os, arch) = (version.os, version.arch);
+                    format!("{}/{}", os.unwrap_or_default(), arch.unwrap_or_default())
+                }
+                platform => platform.to_string(),
+            };
+            let hash = content_hash(&self.opts, &platform, &self.context_digest, &base_ids);
+            self.opts
+                .labels
+                .get_or_insert_with(Default::default)
+                .insert(CONTENT_HASH_LABEL.to_string(), hash.clone());
+
+            let guard = BuildLock::acquire(&hash).await;
+            if self.reuse {
+                let mut found = Image::find(docker, &hash).await?;
+                if let (None, Some(cache)) = (&found, &self.cache) {
+                    found = cache.load(docker, &hash).await?;
+                }
+                if let Some(image) = found {
+                    tracing::info!("reusing image {} for {}", image.id, self.name);
+                    if let Some(tag) = &self.tag {
+                        image.tag(docker, tag).await?;
+                    }
+                    let event = Ok(BuildEvent::Image(image.id));
+                    return Ok(stream::once(ready(event)).left_stream());
+                }
+            }
+            let cache = self.cache;
+            let mut parser = EventParser::default();
+            let tar = self.context.tar_stream(self.dockerfile);
+            let events = docker
+                .build_image(self.opts, None, Some(bollard::body_try_stream(tar)))
+                .map(move |x| match x {
+                    Ok(info) => Ok(parser.parse(info)),
+                    // bollard turns the legacy builder's errors into its own
+                    Err(bollard::errors::Error::DockerStreamError { error }) => {
+                        Ok(parser.parse(BuildInfo {
+                            error: Some(error),
+                            ..Default::default()
+                        }))
+                    }
+                    Err(e) => Err(Error::from(e)),
+                })
+                .map_ok(|events| stream::iter(events.into_iter().map(Ok)))
+                .try_flatten()
+                .and_then(move |event| {
+                    let (cache, hash) = (cache.clone(), hash.clone());
+                    async move {
+                        if let (Some(cache), BuildEvent::Image(id)) = (cache, &event) {
+                            cache.save(docker, id, &hash).await?;
+                        }
+                        Ok(event)
+                    }
+                })
+                // builds of the same image wait until this one is over
+                .inspect(move |_| {
+                    let _ = &guard;
+                });
+            Ok::<_, Error>(events.right_stream())
+        })
+        .try_flatten()
+    }
+}
+
+/// `$NAME` and `${NAME}` in `text` replaced with their value in `args`,
+/// `None` for one that isn't there or another form, e.g. `${NAME:-x}`
+fn substitute_args(text: &str, args: &HashMap<&str, &str>) -> Option<String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        result += &rest[..i];
+        rest = &rest[i + 1..];
+        let name = match rest.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced.find('}')?;
+                rest = &braced[end + 1..];
+                &braced[..end]
+            }
+            None => {
+                let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                name
+            }
+        };
+        if name.is_empty() || !name.chars().all(is_name) {
+            return None;
+        }
+        result += args.get(name)?;
+    }
+    Some(result + rest)
+}
+
+/// Holds the lock of a content hash in [BUILDS], removing it from there
+/// once no build waits for it any more
+struct BuildLock {
+    hash: String,
+    guard: Option<futures::lock::OwnedMutexGuard<()>>,
+}
+
+impl BuildLock {
+    async fn acquire(hash: &str) -> Self {
+        let lock = BUILDS
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default()
+            .clone();
+        Self {
+            hash: hash.to_string(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        let mut builds = BUILDS.lock().unwrap();
+        self.guard.take();
+        // the map's is the last reference, waiting builds hold theirs
+        if builds
+            .get(&self.hash)
+            .is_some_and(|x| Arc::strong_count(x) == 1)
+        {
+            builds.remove(&self.hash);
+        }
+    }
+}
+
+/// Hex sha256 of the options that change the image, the platform it is
+/// built for, the digest of the context, which holds the Dockerfile, and
+/// the ids of the base images
+fn content_hash(
+    opts: &BuildImageOptions,
+    platform: &str,
+    context_digest: &str,
+    base_ids: &[String],
+) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |name: &str, value: &[u8]| {
+        // lengths keep `a` + `bc` apart from `ab` + `c`
+        for x in [name.as_bytes(), value] {
+            hasher.update((x.len() as u64).to_le_bytes());
+            hasher.update(x);
+        }
+    };
+    field("target", opts.target.as_bytes());
+    for (name, value) in opts.buildargs.iter().flatten().sorted() {
+        field("buildarg", format!("{}={}", name, value).as_bytes());
+    }
+    for (key, value) in opts.labels.iter().flatten().sorted() {
+        field("label", format!("{}={}", key, value).as_bytes());
     }
+    field("networkmode", format!("{:?}", opts.networkmode).as_bytes());
+    field("shmsize", format!("{:?}", opts.shmsize).as_bytes());
+    field("extrahosts", format!("{:?}", opts.extrahosts).as_bytes());
+    field("platform", platform.as_bytes());
+    field("context", context_digest.as_bytes());
+    for id in base_ids {
+        field("base", id.as_bytes());
+    }
+    hex::encode(hasher.finalize())
 }
 
 #[derive(Debug, Clone)]
@@ -73,4 +718,201 @@ impl Image {
     pub fn new(id: String) -> Self {
         Self { id }
     }
+
+    /// A local image labelled with `content_hash`, see [CONTENT_HASH_LABEL]
+    pub async fn find(docker: &Docker, content_hash: &str) -> Result<Option<Image>, Error> {
+        let label = format!("{}={}", CONTENT_HASH_LABEL, content_hash);
+        let opts = ListImagesOptions {
+            filters: Some(HashMap::from([("label".to_string(), vec![label])])),
+            ..Default::default()
+        };
+        let images = docker.list_images(Some(opts)).await?;
+        Ok(images.into_iter().next().map(|x| Image::new(x.id)))
+    }
+
+    /// Add `tag` to this image, `latest` if it has no tag
+    pub async fn tag(&self, docker: &Docker, tag: &ImageRef) -> Result<(), Error> {
+        let opts = TagImageOptions {
+            repo: Some(tag.name()),
+            tag: Some(tag.tag().unwrap_or("latest").to_string()),
+        };
+        docker.tag_image(&self.id, Some(opts)).await?;
+        Ok(())
+    }
+
+    /// Pull a prebuilt image instead of building one, `latest`
+    /// if `image` has neither tag nor digest, see [ImagePuller]
+    pub async fn pull(docker: &Docker, image: &ImageRef) -> Result<Image, Error> {
+        ImagePuller::new(image.clone())
+            .with_policy(PullPolicy::Always)
+            .pull(docker)
+            .await
+    }
+
+    /// The local image named `reference`, e.g. `alpine:3.19`
+    pub async fn local(docker: &Docker, reference: &str) -> Result<Option<Image>, Error> {
+        match docker.inspect_image(reference).await {
+            Ok(image) => Ok(image.id.map(Image::new)),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dockerfiles::{Arg, Copy, DockerFile, From};
+
+    use super::ImageBuilder;
+
+    #[test]
+    fn build_options() {
+        let dockerfile = DockerFile::new(From::image("rust").with_tag("${RUST_VERSION}"))
+            .global_arg(Arg::new("RUST_VERSION"));
+        let base = ImageBuilder::new(&dockerfile)
+            .with_label("suite", "matrix")
+            .with_network_mode("host")
+            .with_extra_host("registry", "10.0.0.2");
+        let variants = ["1.79", "1.80"].map(|version| {
+            base.clone()
+                .with_build_arg("RUST_VERSION", version)
+                .with_no_cache(true)
+                .options()
+                .unwrap()
+        });
+        let build_args = variants.each_ref().map(|x| x.buildargs.as_ref().unwrap());
+        assert_eq!(build_args[0]["RUST_VERSION"], "1.79");
+        assert_eq!(build_args[1]["RUST_VERSION"], "1.80");
+        assert_eq!(variants[1].labels.as_ref().unwrap()["suite"], "matrix");
+        assert!(variants[1].nocache && variants[1].rm && !variants[1].forcerm);
+        assert_eq!(variants[1].networkmode.as_deref(), Some("host"));
+        assert_eq!(variants[1].extrahosts.as_deref(), Some("registry:10.0.0.2"));
+
+        let replaced = base.with_extra_host("cache", "10.0.0.3").options().unwrap();
+        assert_eq!(replaced.extrahosts.as_deref(), Some("cache:10.0.0.3"));
+    }
+
+    #[test]
+    fn content_hash() {
+        let opts = ImageBuilder::new("").options().unwrap();
+        let hash = super::content_hash(&opts, "linux/amd64", "sha256:0a", &[]);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(
+            hash,
+            super::content_hash(&opts, "linux/amd64", "sha256:0a", &[])
+        );
+
+        let tagged = ImageBuilder::new("")
+            .with_tag("app:test".parse().unwrap())
+            .options()
+            .unwrap();
+        assert_eq!(
+            hash,
+            super::content_hash(&tagged, "linux/amd64", "sha256:0a", &[])
+        );
+
+        let with_arg = ImageBuilder::new("")
+            .with_build_arg("A", "1")
+            .options()
+            .unwrap();
+        assert_ne!(
+            hash,
+            super::content_hash(&with_arg, "linux/amd64", "sha256:0a", &[])
+        );
+        assert_ne!(
+            hash,
+            super::content_hash(&opts, "linux/amd64", "sha256:0b", &[])
+        );
+
+        // a newer base image builds again
+        let base =
+            |id: &str| super::content_hash(&opts, "linux/amd64", "sha256:0a", &[id.to_string()]);
+        assert_ne!(hash, base("sha256:1b"));
+        assert_ne!(base("sha256:1b"), base("sha256:1c"));
+        let arm = super::content_hash(&opts, "linux/arm64", "sha256:0a", &[]);
+        assert_ne!(hash, arm);
+    }
+
+    #[tokio::test]
+    async fn build_lock() {
+        let hash = "build_lock test";
+        let first = super::BuildLock::acquire(hash).await;
+        let second = tokio::spawn(super::BuildLock::acquire(hash));
+        tokio::task::yield_now().await;
+        assert!(super::BUILDS.lock().unwrap().contains_key(hash));
+        drop(first);
+        let second = second.await.unwrap();
+        assert!(super::BUILDS.lock().unwrap().contains_key(hash));
+        drop(second);
+        assert!(!super::BUILDS.lock().unwrap().contains_key(hash));
+    }
+
+    #[test]
+    fn base_images() {
+        let dockerfile = "ARG VERSION=3.19\nARG REGISTRY\n\
+            FROM alpine:${VERSION} AS base\nFROM base\nFROM $REGISTRY/app:1\n";
+        let request = ImageBuilder::new(dockerfile).request().unwrap();
+        assert!(!request.reuse);
+        let request = ImageBuilder::new(dockerfile)
+            .with_build_arg("VERSION", "3.20")
+            .with_build_arg("REGISTRY", "ghcr.io/me")
+            .request()
+            .unwrap();
+        assert!(request.reuse);
+        assert_eq!(request.base_images, ["alpine:3.20", "ghcr.io/me/app:1"]);
+        let defaulted = dockerfile.replace("${VERSION}", "${VERSION:-3.18}");
+        let request = ImageBuilder::new(&defaulted)
+            .with_build_arg("REGISTRY", "ghcr.io/me")
+            .request()
+            .unwrap();
+        assert!(!request.reuse);
+    }
+
+    #[test]
+    fn reuse() {
+        let dockerfile = "FROM alpine:3.19\n";
+        assert!(ImageBuilder::new(dockerfile).request().unwrap().reuse);
+        let pulled = ImageBuilder::new(dockerfile).with_pull(true);
+        assert!(!pulled.request().unwrap().reuse);
+        let uncached = ImageBuilder::new(dockerfile).with_no_cache(true);
+        assert!(!uncached.request().unwrap().reuse);
+    }
+
+    #[test]
+    fn buildkit_syntax() {
+        let df = DockerFile::new(From::image("alpine"))
+            .then(Copy::new("a.txt", "/a.txt").with_link(true));
+        assert_eq!(
+            ImageBuilder::<&str>::buildkit_syntax(&df),
+            "docker/dockerfile:1"
+        );
+        let df = df.then(Copy::new("src/*/Cargo.toml", "/app/").with_parents(true));
+        assert_eq!(
+            ImageBuilder::<&str>::buildkit_syntax(&df),
+            "docker/dockerfile:1.7-labs"
+        );
+    }
+
+    #[test]
+    fn required_secrets() {
+        let dockerfile = "FROM alpine:3.19\n\
+            RUN --mount=type=secret,id=npmrc cat /run/secrets/npmrc || true\n";
+        assert!(ImageBuilder::new(dockerfile).request().is_ok());
+        let required = dockerfile.replace("id=npmrc", "id=npmrc,required");
+        let err = ImageBuilder::new(&required).request().err().unwrap();
+        assert!(err.to_string().contains("secret npmrc"), "{}", err);
+    }
+
+    #[test]
+    fn context_digest() {
+        let dockerfile = "FROM alpine:3.19\n";
+        let digest = ImageBuilder::new(dockerfile).context_digest().unwrap();
+        assert!(digest.starts_with("sha256:"));
+        let same = ImageBuilder::new(dockerfile).with_label("a", "b");
+        assert_eq!(digest, same.context_digest().unwrap());
+        let other = ImageBuilder::new(dockerfile).with_file("a.txt", "a");
+        assert_ne!(digest, other.context_digest().unwrap());
+    }
 }
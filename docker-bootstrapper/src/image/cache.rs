@@ -0,0 +1,245 @@
+use std::path::{Path, PathBuf};
+
+use bollard::{query_parameters::ImportImageOptions, Docker};
+use bytes::Bytes;
+use color_eyre::eyre::{Error, WrapErr};
+use futures::{stream, Stream, TryStreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::Image;
+
+/// Size of the chunks a tarball is sent to the daemon in
+const CHUNK: usize = 1 << 20;
+
+impl Image {
+    /// Write `image` (a name with its tag, or an id) and its layers to
+    /// `path` as a `docker save` tarball. Saved by name, loading it
+    /// tags it again
+    ///
+    /// The tarball is written next to `path` and renamed to it once
+    /// complete, so `path` is never seen half written
+    pub async fn save(docker: &Docker, image: &str, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let temp = temp_path(path);
+        let written: Result<_, Error> = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            let mut tar = docker.export_image(image);
+            while let Some(chunk) = tar.try_next().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&temp, path).await?;
+            Ok(())
+        }
+        .await;
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&temp).await;
+        }
+        written.wrap_err_with(|| format!("saving {} to {}", image, path.display()))
+    }
+
+    /// Load the images of a `docker save` tarball, like `docker load`
+    pub async fn load(docker: &Docker, path: impl AsRef<Path>) -> Result<Vec<Image>, Error> {
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path)
+            .await
+            .wrap_err_with(|| format!("loading {}", path.display()))?;
+        let name = path.display().to_string();
+        // bollard wants a stream of plain bytes, a failed read ends it and
+        // the daemon rejects the truncated tarball
+        let chunks = stream::unfold(Some(file), move |file| {
+            let name = name.clone();
+            async move {
+                let mut file = file?;
+                let mut chunk = vec![0; CHUNK];
+                match file.read(&mut chunk).await {
+                    Ok(0) => None,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Some((Bytes::from(chunk), Some(file)))
+                    }
+                    Err(e) => {
+                        tracing::error!("reading {}: {}", name, e);
+                        None
+                    }
+                }
+            }
+        });
+        Self::import(docker, chunks).await
+    }
+
+    /// Load a tarball sent in `chunks`, see [Self::load]
+    pub(crate) async fn import(
+        docker: &Docker,
+        chunks: impl Stream<Item = Bytes> + Send + 'static,
+    ) -> Result<Vec<Image>, Error> {
+        let opts = ImportImageOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let lines: Vec<_> = docker
+            .import_image_stream(opts, chunks, None)
+            .map_ok(|info| info.stream.unwrap_or_default())
+            .try_collect()
+            .await?;
+
+        let mut images = Vec::new();
+        for line in lines.iter().flat_map(|x| x.lines()) {
+            if let Some(id) = line.strip_prefix("Loaded image ID: ") {
+                images.push(Image::new(id.trim().to_string()));
+            } else if let Some(name) = line.strip_prefix("Loaded image: ") {
+                images.extend(Image::local(docker, name.trim()).await?);
+            }
+        }
+        Ok(images)
+    }
+}
+
+/// A directory of `docker save` tarballs used instead of building or
+/// pulling, e.g. restored from the artifacts of an earlier CI stage
+/// where it was filled with the images it built and pulled
+#[derive(Debug, Clone)]
+pub struct ImageCache {
+    dir: PathBuf,
+}
+
+impl ImageCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Tarball of the image known as `key`, an image reference such as
+    /// `postgres:16` or the content hash of a build. The characters that
+    /// can't be in a file name are percent-encoded, so keys don't collide
+    pub fn path(&self, key: &str) -> PathBuf {
+        let mut name = String::new();
+        for x in key.chars() {
+            match x {
+                '%' | '/' | ':' | '@' | '\\' => name += &format!("%{:02X}", x as u32),
+                x => name.push(x),
+            }
+        }
+        self.dir.join(format!("{}.tar", name))
+    }
+
+    /// Load the image saved as `key`, `None` if there is none or the
+    /// daemon rejects it, so it is built or pulled instead. A rejected
+    /// tarball is moved aside to `*.tar.bad`
+    pub async fn load(&self, docker: &Docker, key: &str) -> Result<Option<Image>, Error> {
+        let path = self.path(key);
+        if !tokio::fs::try_exists(&path).await? {
+            return Ok(None);
+        }
+        tracing::info!("loading {} from {}", key, path.display());
+        match Image::load(docker, &path).await {
+            Ok(images) => Ok(images.into_iter().next()),
+            Err(e) if is_rejected(&e) => {
+                tracing::warn!("not using the cached {}: {:#}", key, e);
+                // else saving what replaces it would keep it
+                let bad = path.with_extension("tar.bad");
+                if let Err(e) = tokio::fs::rename(&path, &bad).await {
+                    tracing::warn!("moving {} aside: {}", path.display(), e);
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e.wrap_err(format!("loading the cached {}", key))),
+        }
+    }
+
+    /// Save `image` (a name or an id) as `key`, unless it already is
+    pub async fn save(&self, docker: &Docker, image: &str, key: &str) -> Result<(), Error> {
+        let path = self.path(key);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(&self.dir).await?;
+        Image::save(docker, image, path).await
+    }
+}
+
+/// Whether loading failed because the daemon refused the tarball, e.g. a
+/// truncated one, rather than because it couldn't be reached
+fn is_rejected(e: &Error) -> bool {
+    use bollard::errors::Error::*;
+    e.chain()
+        .find_map(|x| x.downcast_ref::<bollard::errors::Error>())
+        .is_some_and(|x| match x {
+            DockerStreamError { .. } => true,
+            DockerResponseServerError {
+                status_code,
+                message,
+            } => {
+                let message = message.to_lowercase();
+                *status_code == 400
+                    || ["tar", "archive", "manifest", "unexpected eof"]
+                        .iter()
+                        .any(|x| message.contains(x))
+            }
+            _ => false,
+        })
+}
+
+/// A hidden file next to `path` to write it to before renaming it
+fn temp_path(path: &Path) -> PathBuf {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bollard::errors::Error::*;
+    use color_eyre::eyre::Error;
+
+    use super::ImageCache;
+
+    #[test]
+    fn cache_paths() {
+        let cache = ImageCache::new("/artifacts/images");
+        assert_eq!(
+            cache.path("localhost:5000/team/app:1.0").to_str(),
+            Some("/artifacts/images/localhost%3A5000%2Fteam%2Fapp%3A1.0.tar")
+        );
+        assert_ne!(cache.path("team/app:1"), cache.path("team_app_1"));
+        assert_ne!(cache.path("a%3A"), cache.path("a:"));
+        assert_eq!(
+            cache.path("3c1b2a").to_str(),
+            Some("/artifacts/images/3c1b2a.tar")
+        );
+    }
+
+    #[test]
+    fn rejected_tarballs() {
+        let rejected = |e: bollard::errors::Error| super::is_rejected(&Error::from(e));
+        assert!(rejected(DockerResponseServerError {
+            status_code: 500,
+            message: "archive/tar: invalid tar header".to_string(),
+        }));
+        assert!(rejected(DockerStreamError {
+            error: "unexpected EOF".to_string(),
+        }));
+        assert!(!rejected(DockerResponseServerError {
+            status_code: 500,
+            message: "no space left on device".to_string(),
+        }));
+        assert!(!rejected(RequestTimeoutError));
+    }
+
+    #[test]
+    fn temp_paths() {
+        let path = Path::new("/artifacts/images/postgres_16.tar");
+        let temp = super::temp_path(path);
+        assert_eq!(temp.parent(), path.parent());
+        let name = temp.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".postgres_16.tar.") && name.ends_with(".tmp"));
+        assert_ne!(temp, super::temp_path(path));
+    }
+}
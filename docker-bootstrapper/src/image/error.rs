@@ -0,0 +1,305 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    panic::Location,
+};
+
+use dockerfiles::DockerFile;
+use itertools::Itertools;
+
+use super::BuildEvent;
+
+/// Lines of output kept for a [BuildError]
+const OUTPUT_TAIL: usize = 20;
+
+/// A failed build, mapped back to the failing instruction when the
+/// builder says which it was
+#[derive(Debug, Clone)]
+pub struct BuildError {
+    /// What was built, its tag or a short content hash
+    pub image: String,
+    /// Number of the failing step, the first being 1 like in `docker build`
+    pub step: Option<usize>,
+    /// The failing instruction, as sent to the daemon
+    pub instruction: Option<String>,
+    /// Exit code of a failing `RUN`
+    pub exit_code: Option<i64>,
+    /// The last lines the failing step printed
+    pub output: Vec<String>,
+    /// Where the instruction was added with [dockerfiles::DockerFile::then]
+    pub location: Option<&'static Location<'static>>,
+    pub message: String,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "building {} failed", self.image)?;
+        if let Some(step) = self.step {
+            write!(f, " at step {}", step)?;
+        }
+        if let Some(instruction) = &self.instruction {
+            write!(f, " `{}`", instruction)?;
+        }
+        if let Some(location) = self.location {
+            write!(f, " (added at {})", location)?;
+        }
+        write!(f, ": {}", self.message)?;
+        for line in &self.output {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Index of a stage and of an instruction in it, see
+/// [DockerFile::locations]
+type Position = (usize, usize);
+
+/// How the instructions sent to the daemon are laid out, to find the one
+/// a step runs from its number
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Layout {
+    /// `ARG`s before the first `FROM`, which the legacy builder numbers too
+    global_args: usize,
+    /// Alias and number of instructions, without the `FROM`, of each stage
+    stages: Vec<(Option<String>, usize)>,
+}
+
+impl Layout {
+    pub fn new(dockerfile: &DockerFile) -> Self {
+        Self {
+            global_args: dockerfile.global_args().len(),
+            stages: dockerfile
+                .stages()
+                .iter()
+                .map(|x| {
+                    (
+                        x.from().alias().map(str::to_string),
+                        x.instructions().count(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Stage and index in it of the instruction run by step `number`, of
+    /// `stage` for BuildKit and of the whole Dockerfile for the legacy
+    /// builder. `None` for `FROM`s and `ARG`s before them
+    fn position(&self, stage: Option<&str>, number: usize) -> Option<Position> {
+        if let Some(stage) = stage {
+            let i = self
+                .stages
+                .iter()
+                .position(|(alias, _)| alias.as_deref() == Some(stage))
+                .or_else(|| stage.strip_prefix("stage-")?.parse().ok())?;
+            // the `FROM` is 1
+            return Some((i, number.checked_sub(2)?));
+        }
+        let mut n = number.checked_sub(self.global_args + 1)?;
+        for (i, (_, len)) in self.stages.iter().enumerate() {
+            match n {
+                0 => return None,
+                n if n <= *len => return Some((i, n - 1)),
+                _ => n -= len + 1,
+            }
+        }
+        None
+    }
+}
+
+/// Follows the [BuildEvent]s of a build to tell where it failed
+pub(crate) struct StepTracker {
+    image: String,
+    layout: Layout,
+    /// Where the instructions at each position were added, with them with
+    /// whitespace collapsed
+    locations: HashMap<Position, (String, &'static Location<'static>)>,
+    /// Number and position of the step of each BuildKit vertex, or of the
+    /// legacy builder's current step under `None`
+    steps: HashMap<Option<String>, (usize, Option<Position>)>,
+    /// The last lines of each BuildKit vertex, which may run in parallel,
+    /// or of the legacy builder's current step under `None`
+    output: HashMap<Option<String>, VecDeque<String>>,
+}
+
+impl StepTracker {
+    pub fn new(
+        image: String,
+        layout: Layout,
+        locations: impl IntoIterator<Item = (Position, String, &'static Location<'static>)>,
+    ) -> Self {
+        Self {
+            image,
+            layout,
+            locations: locations
+                .into_iter()
+                .map(|(position, x, location)| (position, (normalize(&x), location)))
+                .collect(),
+            steps: Default::default(),
+            output: Default::default(),
+        }
+    }
+
+    pub fn track(&mut self, event: &BuildEvent) {
+        match event {
+            BuildEvent::Step {
+                vertex,
+                stage,
+                number,
+                ..
+            } => {
+                if let Some(number) = number {
+                    let position = self.layout.position(stage.as_deref(), *number);
+                    self.steps.insert(vertex.clone(), (*number, position));
+                }
+                self.output.remove(&None);
+            }
+            BuildEvent::Output { vertex, line } => {
+                let output = self.output.entry(vertex.clone()).or_default();
+                if output.len() == OUTPUT_TAIL {
+                    output.pop_front();
+                }
+                output.push_back(line.clone());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn error(
+        &self,
+        vertex: Option<&str>,
+        instruction: Option<&str>,
+        message: &str,
+    ) -> BuildError {
+        let vertex = vertex.map(str::to_string);
+        let output = self.output.get(&vertex);
+        let step = instruction.and(self.steps.get(&vertex));
+        // unless it was changed since, e.g. by `with_optimization`
+        let location = step
+            .and_then(|(_, position)| self.locations.get(position.as_ref()?))
+            .filter(|(x, _)| instruction.is_some_and(|y| *x == normalize(y)))
+            .map(|(_, location)| *location);
+        BuildError {
+            image: self.image.clone(),
+            step: step.map(|(number, _)| *number),
+            instruction: instruction.map(str::to_string),
+            exit_code: exit_code(message),
+            output: output.into_iter().flatten().cloned().collect(),
+            location,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The builders print instructions with their continuations joined
+fn normalize(instruction: &str) -> String {
+    instruction
+        .replace("\\\n", " ")
+        .split_whitespace()
+        .join(" ")
+}
+
+/// From `... returned a non-zero code: 2` (legacy builder) or
+/// `... did not complete successfully: exit code: 2` (BuildKit)
+fn exit_code(message: &str) -> Option<i64> {
+    ["non-zero code: ", "exit code: "]
+        .into_iter()
+        .find_map(|x| message.rsplit_once(x))
+        .and_then(|(_, code)| code.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use dockerfiles::{DockerFile, From, Run};
+
+    use super::{BuildEvent, Layout, StepTracker};
+
+    #[test]
+    fn build_error() {
+        let line = line!() + 2;
+        let df = DockerFile::new(From::image("alpine"))
+            .then(Run::new("make test"))
+            .stage(From::image("alpine"))
+            .then(Run::new("make test"));
+        let tracker = || {
+            let locations = df
+                .locations()
+                .map(|(position, x, location)| (position, x.to_string(), location));
+            StepTracker::new("app:test".to_string(), Layout::new(&df), locations)
+        };
+
+        // the legacy builder numbers the steps of the whole Dockerfile
+        let mut legacy = tracker();
+        let step = |number, instruction: &str| BuildEvent::Step {
+            vertex: None,
+            stage: None,
+            number: Some(number),
+            total: Some(4),
+            instruction: instruction.to_string(),
+        };
+        let events = [
+            step(1, "FROM alpine"),
+            BuildEvent::Output {
+                vertex: None,
+                line: "pulling".to_string(),
+            },
+            step(2, "RUN make test"),
+            step(3, "FROM alpine"),
+            step(4, "RUN make test"),
+        ];
+        events.iter().for_each(|x| legacy.track(x));
+        let err = legacy.error(
+            None,
+            Some("RUN make test"),
+            "The command '/bin/sh -c make test' returned a non-zero code: 2",
+        );
+        assert_eq!(err.step, Some(4));
+        assert_eq!(err.exit_code, Some(2));
+        let location = err.location.unwrap();
+        assert_eq!(location.line(), line + 2);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "building app:test failed at step 4 `RUN make test` (added at {}): {}",
+                location, err.message
+            )
+        );
+
+        // BuildKit numbers them within each stage, which may run in
+        // parallel and interleave their logs
+        let mut buildkit = tracker();
+        for (vertex, stage) in [("sha256:a", "stage-0"), ("sha256:b", "stage-1")] {
+            buildkit.track(&BuildEvent::Step {
+                vertex: Some(vertex.to_string()),
+                stage: Some(stage.to_string()),
+                number: Some(2),
+                total: Some(2),
+                instruction: "RUN make test".to_string(),
+            });
+        }
+        for i in 0..25 {
+            for vertex in ["sha256:a", "sha256:b"] {
+                buildkit.track(&BuildEvent::Output {
+                    vertex: Some(vertex.to_string()),
+                    line: format!("{} line {}", vertex, i),
+                });
+            }
+        }
+        let err = buildkit.error(
+            Some("sha256:a"),
+            Some("RUN make test"),
+            "process \"/bin/sh -c make test\" did not complete successfully: exit code: 2",
+        );
+        assert_eq!(err.step, Some(2));
+        assert_eq!(err.exit_code, Some(2));
+        assert_eq!(err.location.unwrap().line(), line);
+        assert_eq!(err.output.len(), 20);
+        assert_eq!(err.output[0], "sha256:a line 5");
+        assert!(err.output.iter().all(|x| x.starts_with("sha256:a")));
+
+        let err = buildkit.error(None, None, "no image id");
+        assert_eq!((err.step, err.exit_code, err.location), (None, None, None));
+    }
+}
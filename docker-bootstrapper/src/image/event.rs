@@ -0,0 +1,365 @@
+use std::{collections::HashSet, fmt::Display};
+
+use bollard::{
+    moby::buildkit::v1::StatusResponse,
+    models::{BuildInfo, BuildInfoAux},
+};
+
+/// What happens during a build, see [crate::ImageBuilder::build_stream]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildEvent {
+    /// An instruction started, numbered when the builder says so. BuildKit
+    /// also gives the digest of its vertex and the stage it is in, by alias
+    /// or as `stage-1`, which it numbers it within
+    Step {
+        vertex: Option<String>,
+        stage: Option<String>,
+        number: Option<usize>,
+        total: Option<usize>,
+        instruction: String,
+    },
+    /// A line printed by the build, e.g. by a `RUN`, with the digest of
+    /// the BuildKit vertex printing it
+    Output {
+        vertex: Option<String>,
+        line: String,
+    },
+    /// Progress of pulling a base image or one of its layers
+    Pull {
+        id: Option<String>,
+        status: String,
+        current: Option<i64>,
+        total: Option<i64>,
+    },
+    /// Progress of pushing an image, see [crate::Image::push_stream]. The
+    /// daemon says which layer it is about, but bollard drops it
+    Push {
+        status: String,
+        current: Option<i64>,
+        total: Option<i64>,
+    },
+    /// An instruction wasn't run, its layer came from the cache
+    CacheHit(String),
+    /// The build failed, at `instruction` when the builder says where,
+    /// and in which BuildKit vertex
+    Error {
+        vertex: Option<String>,
+        instruction: Option<String>,
+        message: String,
+    },
+    /// The built (or reused) image
+    Image(String),
+}
+
+impl BuildEvent {
+    /// Log through `tracing`, `image` naming what is being built
+    pub fn trace(&self, image: &str) {
+        match self {
+            BuildEvent::Step { .. } | BuildEvent::Image(_) => {
+                tracing::info!(image, "{}", self)
+            }
+            BuildEvent::Output { .. } => tracing::debug!(image, "{}", self),
+            BuildEvent::Pull { .. } | BuildEvent::Push { .. } | BuildEvent::CacheHit(_) => {
+                tracing::trace!(image, "{}", self)
+            }
+            BuildEvent::Error { .. } => tracing::error!(image, "{}", self),
+        }
+    }
+
+    /// Events worth showing next to container logs, skipping progress
+    pub fn is_rendered(&self) -> bool {
+        !matches!(self, BuildEvent::Pull { .. } | BuildEvent::Push { .. })
+    }
+}
+
+/// Turns the daemon's messages into [BuildEvent]s, for both the legacy
+/// builder and BuildKit
+#[derive(Default)]
+pub(crate) struct EventParser {
+    /// The legacy builder reports cache hits after the step
+    last_step: Option<String>,
+    /// BuildKit repeats vertexes as their state changes
+    started: HashSet<String>,
+}
+
+impl EventParser {
+    pub fn parse(&mut self, info: BuildInfo) -> Vec<BuildEvent> {
+        let mut events = Vec::new();
+        if let Some(stream) = &info.stream {
+            for line in stream.lines().map(str::trim_end).filter(|x| !x.is_empty()) {
+                events.push(self.parse_line(line));
+            }
+        }
+        if let Some(status) = info.status {
+            let progress = info.progress_detail.unwrap_or_default();
+            events.push(BuildEvent::Pull {
+                id: info.id,
+                status,
+                current: progress.current,
+                total: progress.total,
+            });
+        }
+        if let Some(message) = info.error {
+            // the legacy builder fails the step it started last
+            events.push(BuildEvent::Error {
+                vertex: None,
+                instruction: self.last_step.clone(),
+                message,
+            });
+        }
+        match info.aux {
+            Some(BuildInfoAux::Default(x)) => events.extend(x.id.map(BuildEvent::Image)),
+            Some(BuildInfoAux::BuildKit(status)) => self.parse_buildkit(status, &mut events),
+            None => {}
+        }
+        events
+    }
+
+    /// e.g. `Step 2/5 : RUN apk add curl` or ` ---> Using cache`
+    fn parse_line(&mut self, line: &str) -> BuildEvent {
+        if let Some((step, instruction)) =
+            line.strip_prefix("Step ").and_then(|x| x.split_once(" : "))
+        {
+            self.last_step = Some(instruction.to_string());
+            let (number, total) = step_numbers(step);
+            return BuildEvent::Step {
+                vertex: None,
+                stage: None,
+                number,
+                total,
+                instruction: instruction.to_string(),
+            };
+        }
+        match (line.trim(), &self.last_step) {
+            ("---> Using cache", Some(step)) => BuildEvent::CacheHit(step.clone()),
+            _ => BuildEvent::Output {
+                vertex: None,
+                line: line.to_string(),
+            },
+        }
+    }
+
+    fn parse_buildkit(&mut self, status: StatusResponse, events: &mut Vec<BuildEvent>) {
+        let mut errors = Vec::new();
+        for vertex in status.vertexes {
+            // e.g. `[builder 2/5] RUN apk add curl` or `[internal] load .dockerignore`
+            let (prefix, instruction) = match vertex.name.strip_prefix('[') {
+                Some(x) => x.split_once("] ").unwrap_or(("", x)),
+                None => ("", vertex.name.as_str()),
+            };
+            if vertex.cached && self.started.insert(vertex.digest.clone()) {
+                events.push(BuildEvent::CacheHit(instruction.to_string()));
+            } else if vertex.started.is_some() && self.started.insert(vertex.digest.clone()) {
+                let (stage, step) = prefix.rsplit_once(' ').unzip();
+                let (number, total) = step_numbers(step.unwrap_or_default());
+                events.push(BuildEvent::Step {
+                    vertex: Some(vertex.digest.clone()),
+                    stage: stage.map(str::to_string),
+                    number,
+                    total,
+                    instruction: instruction.to_string(),
+                });
+            }
+            if !vertex.error.is_empty() {
+                errors.push(BuildEvent::Error {
+                    vertex: Some(vertex.digest.clone()),
+                    instruction: Some(instruction.to_string()),
+                    message: vertex.error,
+                });
+            }
+        }
+        for status in status.statuses {
+            events.push(BuildEvent::Pull {
+                id: Some(status.id).filter(|x| !x.is_empty()),
+                status: status.name,
+                current: Some(status.current),
+                total: Some(status.total).filter(|x| *x > 0),
+            });
+        }
+        for log in status.logs {
+            let msg = String::from_utf8_lossy(&log.msg);
+            for line in msg.lines().map(str::trim_end).filter(|x| !x.is_empty()) {
+                events.push(BuildEvent::Output {
+                    vertex: Some(log.vertex.clone()),
+                    line: line.to_string(),
+                });
+            }
+        }
+        // a failing vertex sends its last logs along with its error, which
+        // ends the build, so they go first
+        events.extend(errors);
+    }
+}
+
+/// `2/5` as its two numbers
+fn step_numbers(step: &str) -> (Option<usize>, Option<usize>) {
+    match step.split_once('/') {
+        Some((number, total)) => (number.parse().ok(), total.parse().ok()),
+        None => (None, None),
+    }
+}
+
+impl Display for BuildEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildEvent::Step {
+                number: Some(number),
+                total: Some(total),
+                instruction,
+                ..
+            } => write!(f, "[{}/{}] {}", number, total, instruction),
+            BuildEvent::Step { instruction, .. } => write!(f, "{}", instruction),
+            BuildEvent::Output { line, .. } => write!(f, "{}", line),
+            BuildEvent::Pull {
+                id,
+                status,
+                current,
+                total,
+            } => {
+                if let Some(id) = id {
+                    write!(f, "{}: ", id)?;
+                }
+                write!(f, "{}", status)?;
+                match (current, total) {
+                    (Some(current), Some(total)) => write!(f, " {}/{}", current, total),
+                    _ => Ok(()),
+                }
+            }
+            BuildEvent::Push {
+                status,
+                current: Some(current),
+                total: Some(total),
+            } => write!(f, "{} {}/{}", status, current, total),
+            BuildEvent::Push { status, .. } => write!(f, "{}", status),
+            BuildEvent::CacheHit(instruction) => write!(f, "CACHED {}", instruction),
+            BuildEvent::Error {
+                instruction: Some(instruction),
+                message,
+                ..
+            } => write!(f, "error: {}: {}", instruction, message),
+            BuildEvent::Error { message, .. } => write!(f, "error: {}", message),
+            BuildEvent::Image(id) => write!(f, "built {}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::{
+        moby::buildkit::v1::{StatusResponse, Vertex, VertexLog},
+        models::{BuildInfo, BuildInfoAux, ImageId, ProgressDetail},
+    };
+
+    use super::{BuildEvent, EventParser};
+
+    #[test]
+    fn parse_legacy_events() {
+        let mut parser = EventParser::default();
+        let stream = |x: &str| BuildInfo {
+            stream: Some(x.to_string()),
+            ..Default::default()
+        };
+        let mut events = parser.parse(stream("Step 2/3 : RUN apk add curl\n"));
+        events.extend(parser.parse(stream(" ---> Using cache\n ---> 3c1b2a\n")));
+        events.extend(parser.parse(BuildInfo {
+            id: Some("4abcf2066143".to_string()),
+            status: Some("Downloading".to_string()),
+            progress_detail: Some(ProgressDetail {
+                current: Some(10),
+                total: Some(100),
+            }),
+            ..Default::default()
+        }));
+        events.extend(parser.parse(BuildInfo {
+            aux: Some(BuildInfoAux::Default(ImageId {
+                id: Some("sha256:3c1b2a".to_string()),
+            })),
+            ..Default::default()
+        }));
+        assert_eq!(
+            events,
+            [
+                BuildEvent::Step {
+                    vertex: None,
+                    stage: None,
+                    number: Some(2),
+                    total: Some(3),
+                    instruction: "RUN apk add curl".to_string()
+                },
+                BuildEvent::CacheHit("RUN apk add curl".to_string()),
+                BuildEvent::Output {
+                    vertex: None,
+                    line: " ---> 3c1b2a".to_string()
+                },
+                BuildEvent::Pull {
+                    id: Some("4abcf2066143".to_string()),
+                    status: "Downloading".to_string(),
+                    current: Some(10),
+                    total: Some(100),
+                },
+                BuildEvent::Image("sha256:3c1b2a".to_string()),
+            ]
+        );
+        assert_eq!(events[3].to_string(), "4abcf2066143: Downloading 10/100");
+        let error = BuildInfo {
+            error: Some("The command '/bin/sh -c apk add curl' returned a non-zero code: 1".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            parser.parse(error)[0].to_string(),
+            "error: RUN apk add curl: \
+            The command '/bin/sh -c apk add curl' returned a non-zero code: 1"
+        );
+    }
+
+    #[test]
+    fn parse_buildkit_events() {
+        let mut parser = EventParser::default();
+        let vertex = Vertex {
+            digest: "sha256:a".to_string(),
+            name: "[builder 2/5] RUN make".to_string(),
+            started: Some(Default::default()),
+            ..Default::default()
+        };
+        let status = |vertexes, logs| BuildInfo {
+            aux: Some(BuildInfoAux::BuildKit(StatusResponse {
+                vertexes,
+                logs,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut events = parser.parse(status(vec![vertex.clone()], vec![]));
+        let log = VertexLog {
+            vertex: "sha256:a".to_string(),
+            msg: b"cc -o app main.c\n".to_vec(),
+            ..Default::default()
+        };
+        let failed = Vertex {
+            error: "exit code: 2".to_string(),
+            ..vertex
+        };
+        events.extend(parser.parse(status(vec![failed], vec![log])));
+        assert_eq!(
+            events,
+            [
+                BuildEvent::Step {
+                    vertex: Some("sha256:a".to_string()),
+                    stage: Some("builder".to_string()),
+                    number: Some(2),
+                    total: Some(5),
+                    instruction: "RUN make".to_string()
+                },
+                BuildEvent::Output {
+                    vertex: Some("sha256:a".to_string()),
+                    line: "cc -o app main.c".to_string()
+                },
+                BuildEvent::Error {
+                    vertex: Some("sha256:a".to_string()),
+                    instruction: Some("RUN make".to_string()),
+                    message: "exit code: 2".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use bollard::{
+    models::{BollardDate, HistoryResponseItem, ImageInspect},
+    Docker,
+};
+use color_eyre::eyre::{Error, WrapErr};
+
+use super::Image;
+
+/// What the daemon knows about an image, see [Image::inspect]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    pub id: String,
+    /// e.g. `alpine:3.19`
+    pub tags: Vec<String>,
+    /// Bytes of all its layers, uncompressed
+    pub size: u64,
+    /// Digests of the uncompressed layers, the base image's first
+    pub layers: Vec<String>,
+    /// `NAME=value`
+    pub env: Vec<String>,
+    pub entrypoint: Vec<String>,
+    pub cmd: Vec<String>,
+    /// e.g. `8080/tcp`, sorted
+    pub exposed_ports: Vec<String>,
+    pub working_dir: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    /// e.g. `amd64`
+    pub architecture: String,
+    pub os: String,
+    /// Unset for images made without a date, e.g. reproducibly
+    pub created: Option<BollardDate>,
+}
+
+impl ImageMetadata {
+    /// Value of the environment variable `name`
+    pub fn env_var(&self, name: &str) -> Option<&str> {
+        self.env.iter().find_map(|x| {
+            x.split_once('=')
+                .filter(|(x, _)| *x == name)
+                .map(|(_, value)| value)
+        })
+    }
+}
+
+impl From<ImageInspect> for ImageMetadata {
+    fn from(image: ImageInspect) -> Self {
+        let config = image.config.unwrap_or_default();
+        let mut exposed_ports: Vec<_> = config
+            .exposed_ports
+            .unwrap_or_default()
+            .into_keys()
+            .collect();
+        exposed_ports.sort();
+        Self {
+            id: image.id.unwrap_or_default(),
+            tags: image.repo_tags.unwrap_or_default(),
+            size: image.size.unwrap_or_default().max(0) as u64,
+            layers: image.root_fs.and_then(|x| x.layers).unwrap_or_default(),
+            env: config.env.unwrap_or_default(),
+            entrypoint: config.entrypoint.unwrap_or_default(),
+            cmd: config.cmd.unwrap_or_default(),
+            exposed_ports,
+            working_dir: config.working_dir.filter(|x| !x.is_empty()),
+            labels: config.labels.unwrap_or_default().into_iter().collect(),
+            architecture: image.architecture.unwrap_or_default(),
+            os: image.os.unwrap_or_default(),
+            // the zero time of Go or the epoch
+            created: image.created.filter(|x| x.timestamp() > 0),
+        }
+    }
+}
+
+/// A step of the build of an image, see [Image::history]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerHistory {
+    /// Id of the image of this step, `<missing>` unless it was built on
+    /// this daemon
+    pub id: String,
+    /// The instruction, e.g. `RUN /bin/sh -c apk add curl # buildkit`
+    pub created_by: String,
+    /// Bytes the step added, 0 if it only changed the config
+    pub size: u64,
+    /// Unix time
+    pub created: i64,
+    pub tags: Vec<String>,
+    pub comment: String,
+}
+
+impl From<HistoryResponseItem> for LayerHistory {
+    fn from(item: HistoryResponseItem) -> Self {
+        Self {
+            id: item.id,
+            created_by: item.created_by,
+            size: item.size.max(0) as u64,
+            created: item.created,
+            tags: item.tags,
+            comment: item.comment,
+        }
+    }
+}
+
+impl Image {
+    /// Size, layers, config and platform of this image, e.g. to check
+    /// what a [dockerfiles::DockerFile] produced
+    pub async fn inspect(&self, docker: &Docker) -> Result<ImageMetadata, Error> {
+        let image = docker
+            .inspect_image(&self.id)
+            .await
+            .wrap_err_with(|| format!("inspecting image {}", self.id))?;
+        Ok(image.into())
+    }
+
+    /// The steps that made this image, the last first, e.g. to find the
+    /// one that makes it too large
+    pub async fn history(&self, docker: &Docker) -> Result<Vec<LayerHistory>, Error> {
+        let history = docker
+            .image_history(&self.id)
+            .await
+            .wrap_err_with(|| format!("reading history of image {}", self.id))?;
+        Ok(history.into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bollard::models::{ImageConfig, ImageInspect, ImageInspectRootFs};
+
+    use super::ImageMetadata;
+
+    #[test]
+    fn image_metadata() {
+        let image = ImageInspect {
+            id: Some("sha256:3c1b".to_string()),
+            repo_tags: Some(vec!["app:test".to_string()]),
+            created: Some("0001-01-01T00:00:00Z".parse().unwrap()),
+            size: Some(7_340_032),
+            architecture: Some("arm64".to_string()),
+            os: Some("linux".to_string()),
+            root_fs: Some(ImageInspectRootFs {
+                typ: "layers".to_string(),
+                layers: Some(vec!["sha256:aa".to_string(), "sha256:bb".to_string()]),
+            }),
+            config: Some(ImageConfig {
+                env: Some(vec!["PATH=/bin".to_string(), "MODE=a=b".to_string()]),
+                entrypoint: Some(vec!["/app".to_string()]),
+                exposed_ports: Some(HashMap::from([
+                    ("9090/udp".to_string(), HashMap::new()),
+                    ("8080/tcp".to_string(), HashMap::new()),
+                ])),
+                labels: Some(HashMap::from([("suite".to_string(), "e2e".to_string())])),
+                working_dir: Some(String::new()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let metadata = ImageMetadata::from(image);
+        assert_eq!(metadata.size, 7_340_032);
+        assert_eq!(metadata.layers, ["sha256:aa", "sha256:bb"]);
+        assert_eq!(metadata.exposed_ports, ["8080/tcp", "9090/udp"]);
+        assert_eq!(metadata.env_var("MODE"), Some("a=b"));
+        assert_eq!(metadata.env_var("HOME"), None);
+        assert_eq!(metadata.entrypoint, ["/app"]);
+        assert!(metadata.cmd.is_empty());
+        assert_eq!(metadata.labels["suite"], "e2e");
+        assert_eq!(metadata.working_dir, None);
+        assert_eq!(metadata.created, None);
+    }
+}
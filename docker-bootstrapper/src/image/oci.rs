@@ -0,0 +1,308 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use bollard::Docker;
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Error};
+use dockerfiles::ImageRef;
+use futures::{future::ready, stream};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::Image;
+
+const MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+/// Contents of a file in the image
+#[derive(Debug, Clone)]
+enum Source {
+    Bytes(Vec<u8>),
+    /// Read when the image is written
+    Path(PathBuf),
+}
+
+/// Assembles a single layer image from files, e.g. a static test binary,
+/// without a Dockerfile or the daemon building anything: the image is
+/// written as an OCI image layout and loaded with `docker load`
+#[derive(Debug, Clone)]
+pub struct OciImageBuilder {
+    files: BTreeMap<String, (Source, u32)>,
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    env: Vec<String>,
+    workdir: Option<String>,
+    labels: BTreeMap<String, String>,
+    tag: Option<ImageRef>,
+    architecture: String,
+}
+
+impl Default for OciImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OciImageBuilder {
+    pub fn new() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            x => x,
+        };
+        Self {
+            files: Default::default(),
+            entrypoint: Default::default(),
+            cmd: Default::default(),
+            env: Default::default(),
+            workdir: Default::default(),
+            labels: Default::default(),
+            tag: Default::default(),
+            architecture: architecture.to_string(),
+        }
+    }
+
+    /// A file at the absolute `path` in the image
+    ///
+    /// # Panics
+    /// If `path` isn't absolute or is `/`
+    #[track_caller]
+    pub fn with_file(self, path: impl AsRef<str>, contents: impl Into<Vec<u8>>, mode: u32) -> Self {
+        self.with_source(path.as_ref(), Source::Bytes(contents.into()), mode)
+    }
+
+    /// A file at the absolute `path` in the image with the contents of
+    /// `local`, e.g. [std::env::current_exe] with mode `0o755`
+    ///
+    /// # Panics
+    /// If `path` isn't absolute or is `/`
+    #[track_caller]
+    pub fn with_local_file(
+        self,
+        path: impl AsRef<str>,
+        local: impl Into<PathBuf>,
+        mode: u32,
+    ) -> Self {
+        self.with_source(path.as_ref(), Source::Path(local.into()), mode)
+    }
+
+    #[track_caller]
+    fn with_source(mut self, path: &str, source: Source, mode: u32) -> Self {
+        let relative = path.strip_prefix('/').map(|x| x.trim_end_matches('/'));
+        assert!(
+            relative
+                .is_some_and(|x| !x.is_empty()
+                    && x.split('/').all(|x| !x.is_empty() && x != "." && x != "..")),
+            "invalid path in image: {}",
+            path
+        );
+        self.files
+            .insert(relative.unwrap().to_string(), (source, mode));
+        self
+    }
+
+    pub fn with_entrypoint(mut self, entrypoint: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.entrypoint = Some(entrypoint.into_iter().map(|x| x.to_string()).collect());
+        self
+    }
+
+    pub fn with_cmd(mut self, cmd: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.cmd = Some(cmd.into_iter().map(|x| x.to_string()).collect());
+        self
+    }
+
+    pub fn with_env(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.env
+            .push(format!("{}={}", name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_workdir(mut self, workdir: impl ToString) -> Self {
+        self.workdir = Some(workdir.to_string());
+        self
+    }
+
+    pub fn with_label(mut self, key: impl ToString, value: impl ToString) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_tag(mut self, tag: ImageRef) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// e.g. `arm64`, that of this process by default
+    pub fn with_architecture(mut self, architecture: impl ToString) -> Self {
+        self.architecture = architecture.to_string();
+        self
+    }
+
+    /// The layer, an uncompressed tar of the files with their parent
+    /// directories, owned by root and dated to the epoch so the same
+    /// files always make the same layer
+    fn layer(&self) -> io::Result<Vec<u8>> {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut dirs = Vec::new();
+        for (path, (source, mode)) in &self.files {
+            for (i, _) in path.match_indices('/') {
+                let parent = &path[..i + 1];
+                if !dirs.contains(&parent) {
+                    append(&mut tar, tar::EntryType::Directory, parent, 0o755, &[])?;
+                    dirs.push(parent);
+                }
+            }
+            let contents = match source {
+                Source::Bytes(x) => x.clone(),
+                Source::Path(x) => std::fs::read(x)?,
+            };
+            append(&mut tar, tar::EntryType::Regular, path, *mode, &contents)?;
+        }
+        tar.into_inner()
+    }
+
+    /// Write the image to `out` as a tar of an OCI image layout, with the
+    /// `manifest.json` of `docker save` for daemons without OCI support.
+    /// Returns the digest of the image's config, which daemons without
+    /// the containerd image store use as the image's id
+    pub fn write_tar(&self, out: impl Write) -> io::Result<String> {
+        let layer = self.layer()?;
+        let layer_digest = digest(&layer);
+        let mut config = json!({
+            "architecture": self.architecture,
+            "os": "linux",
+            "config": {
+                "Env": self.env,
+                "Labels": self.labels,
+            },
+            "rootfs": { "type": "layers", "diff_ids": [layer_digest] },
+            "history": [{ "created_by": "docker-bootstrapper" }],
+        });
+        if let Some(x) = &self.entrypoint {
+            config["config"]["Entrypoint"] = json!(x);
+        }
+        if let Some(x) = &self.cmd {
+            config["config"]["Cmd"] = json!(x);
+        }
+        if let Some(x) = &self.workdir {
+            config["config"]["WorkingDir"] = json!(x);
+        }
+        let config = serde_json::to_vec(&config)?;
+        let config_digest = digest(&config);
+        let manifest = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_TYPE,
+            "config": { "mediaType": CONFIG_TYPE, "digest": config_digest, "size": config.len() },
+            "layers": [{ "mediaType": LAYER_TYPE, "digest": layer_digest, "size": layer.len() }],
+        }))?;
+        let manifest_digest = digest(&manifest);
+
+        let mut annotations = BTreeMap::new();
+        let mut repo_tags = Vec::new();
+        if let Some(tag) = &self.tag {
+            let name = tag.tag().unwrap_or("latest");
+            let full = format!("{}:{}", tag.name(), name);
+            annotations.insert("io.containerd.image.name", full.clone());
+            annotations.insert("org.opencontainers.image.ref.name", name.to_string());
+            repo_tags.push(full);
+        }
+        let index = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [{
+                "mediaType": MANIFEST_TYPE,
+                "digest": manifest_digest,
+                "size": manifest.len(),
+                "annotations": annotations,
+            }],
+        }))?;
+        let docker_manifest = serde_json::to_vec(&json!([{
+            "Config": blob(&config_digest),
+            "RepoTags": repo_tags,
+            "Layers": [blob(&layer_digest)],
+        }]))?;
+
+        let mut tar = tar::Builder::new(out);
+        let layout = br#"{"imageLayoutVersion":"1.0.0"}"#;
+        append(
+            &mut tar,
+            tar::EntryType::Regular,
+            "oci-layout",
+            0o644,
+            layout,
+        )?;
+        append(
+            &mut tar,
+            tar::EntryType::Regular,
+            "index.json",
+            0o644,
+            &index,
+        )?;
+        append(
+            &mut tar,
+            tar::EntryType::Regular,
+            "manifest.json",
+            0o644,
+            &docker_manifest,
+        )?;
+        for dir in ["blobs/", "blobs/sha256/"] {
+            append(&mut tar, tar::EntryType::Directory, dir, 0o755, &[])?;
+        }
+        for (digest, blob_contents) in [
+            (&config_digest, &config),
+            (&manifest_digest, &manifest),
+            (&layer_digest, &layer),
+        ] {
+            append(
+                &mut tar,
+                tar::EntryType::Regular,
+                &blob(digest),
+                0o644,
+                blob_contents,
+            )?;
+        }
+        tar.into_inner()?.flush()?;
+        Ok(config_digest)
+    }
+
+    /// Load the image into the daemon
+    pub async fn build(self, docker: &Docker) -> Result<Image, Error> {
+        let mut tar = Vec::new();
+        let config_digest = self.write_tar(&mut tar)?;
+        let images = Image::import(docker, stream::once(ready(Bytes::from(tar)))).await?;
+        images
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("the daemon loaded no image from {}", config_digest))
+    }
+}
+
+fn append(
+    tar: &mut tar::Builder<impl Write>,
+    entry_type: tar::EntryType,
+    path: &str,
+    mode: u32,
+    contents: &[u8],
+) -> io::Result<()> {
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(entry_type);
+    header.set_size(contents.len() as u64);
+    header.set_mode(mode);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    tar.append_data(&mut header, path, contents)
+}
+
+fn digest(contents: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(contents)))
+}
+
+/// Path of the blob with `digest` in the layout
+fn blob(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
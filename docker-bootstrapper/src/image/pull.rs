@@ -0,0 +1,262 @@
+use std::{path::PathBuf, pin::pin};
+
+use bollard::{auth::DockerCredentials, query_parameters::CreateImageOptions, Docker};
+use color_eyre::eyre::{eyre, Error};
+use dockerfiles::ImageRef;
+use futures::{future::ready, stream, Stream, StreamExt, TryStreamExt};
+
+use super::{BuildEvent, Image, ImageCache, ImageMetadata};
+use crate::DockerConfig;
+
+/// When [ImagePuller] asks the registry for the image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PullPolicy {
+    /// Even if it is present, to get the latest version of the tag
+    Always,
+    /// Only if it isn't present
+    #[default]
+    IfMissing,
+    /// Never, failing if it isn't present
+    Never,
+}
+
+/// Gets a prebuilt image from a registry, authenticating with explicit
+/// credentials or those of the Docker client config
+#[derive(Debug, Clone)]
+pub struct ImagePuller {
+    image: ImageRef,
+    policy: PullPolicy,
+    platform: Option<String>,
+    credentials: Option<DockerCredentials>,
+    config: Option<DockerConfig>,
+    cache: Option<ImageCache>,
+}
+
+impl ImagePuller {
+    /// Pull `image`, `latest` if it has neither tag nor digest
+    pub fn new(image: ImageRef) -> Self {
+        Self {
+            image,
+            policy: Default::default(),
+            platform: Default::default(),
+            credentials: Default::default(),
+            config: Default::default(),
+            cache: Default::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: PullPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// e.g. `linux/arm64`, the daemon's own by default
+    pub fn with_platform(mut self, platform: impl ToString) -> Self {
+        self.platform = Some(platform.to_string());
+        self
+    }
+
+    /// Log in with these instead of looking into the Docker config
+    pub fn with_credentials(mut self, credentials: DockerCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Look for credentials in `config` instead of [DockerConfig::load]
+    pub fn with_docker_config(mut self, config: DockerConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Load the image from `dir` instead of pulling it if it was saved
+    /// there, and save it there once pulled, see [ImageCache]
+    pub fn with_image_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache = Some(ImageCache::new(dir));
+        self
+    }
+
+    fn reference(&self) -> String {
+        daemon_reference(&self.image)
+    }
+
+    async fn credentials(&self) -> Result<Option<DockerCredentials>, Error> {
+        if let Some(credentials) = &self.credentials {
+            return Ok(Some(credentials.clone()));
+        }
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => DockerConfig::load()?,
+        };
+        config.credentials(self.image.registry()).await
+    }
+
+    /// The local `image` if it is for [Self::with_platform]'s platform
+    async fn for_platform(&self, docker: &Docker, image: Image) -> Result<Option<Image>, Error> {
+        let Some(platform) = &self.platform else {
+            return Ok(Some(image));
+        };
+        let metadata = image.inspect(docker).await?;
+        match is_platform(&metadata, platform) {
+            true => Ok(Some(image)),
+            false => {
+                tracing::debug!(
+                    "image {} is for {}/{}, not {}",
+                    image.id,
+                    metadata.os,
+                    metadata.architecture,
+                    platform
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn pull(self, docker: &Docker) -> Result<Image, Error> {
+        let reference = self.reference();
+        let mut events = pin!(self.pull_stream(docker));
+        let mut id = None;
+        while let Some(event) = events.try_next().await? {
+            event.trace(&reference);
+            if let BuildEvent::Image(x) = event {
+                id = Some(x);
+            }
+        }
+        id.map(Image::new)
+            .ok_or_else(|| eyre!("pulled image {} without id", reference))
+    }
+
+    /// Pull, yielding the progress of each layer as [BuildEvent::Pull]s
+    /// like a build does, and the image as the last event
+    pub fn pull_stream(
+        self,
+        docker: &Docker,
+    ) -> impl Stream<Item = Result<BuildEvent, Error>> + '_ {
+        stream::once(async move {
+            let reference = self.reference();
+            if self.policy != PullPolicy::Always {
+                let mut found = Image::local(docker, &reference).await?;
+                if let (None, Some(cache)) = (&found, &self.cache) {
+                    found = cache.load(docker, &reference).await?;
+                }
+                // the daemon keeps one image per name, maybe of another platform
+                if let Some(image) = found {
+                    found = self.for_platform(docker, image).await?;
+                }
+                if let Some(image) = found {
+                    let event = Ok(BuildEvent::Image(image.id));
+                    return Ok(stream::once(ready(event)).left_stream());
+                }
+            }
+            if self.policy == PullPolicy::Never {
+                return Err(eyre!(
+                    "image {} isn't present{} and its pull policy is never",
+                    reference,
+                    self.platform
+                        .as_ref()
+                        .map_or(String::new(), |x| format!(" for {}", x))
+                ));
+            }
+            let opts = CreateImageOptions {
+                from_image: Some(self.image.name()),
+                tag: Some(match (self.image.digest(), self.image.tag()) {
+                    (Some(digest), _) => digest.to_string(),
+                    (None, tag) => tag.unwrap_or("latest").to_string(),
+                }),
+                platform: self.platform.clone().unwrap_or_default(),
+                ..Default::default()
+            };
+            let credentials = self.credentials().await?;
+            let progress = docker
+                .create_image(Some(opts), None, credentials)
+                .err_into::<Error>()
+                .try_filter_map(|info| {
+                    ready(Ok(info.status.map(|status| {
+                        let progress = info.progress_detail.unwrap_or_default();
+                        BuildEvent::Pull {
+                            id: info.id,
+                            status,
+                            current: progress.current,
+                            total: progress.total,
+                        }
+                    })))
+                });
+            let image = stream::once(async move {
+                let image = Image::local(docker, &reference).await?;
+                let image = image.ok_or_else(|| eyre!("pulled image {} is missing", reference))?;
+                if let Some(cache) = &self.cache {
+                    cache.save(docker, &reference, &reference).await?;
+                }
+                Ok(BuildEvent::Image(image.id))
+            });
+            Ok::<_, Error>(progress.chain(image).right_stream())
+        })
+        .try_flatten()
+    }
+}
+
+/// Whether an image is for `platform`, e.g. `linux/arm64/v8`, whose
+/// variant isn't compared
+fn is_platform(image: &ImageMetadata, platform: &str) -> bool {
+    let mut parts = platform.split('/');
+    let (os, arch) = (parts.next(), parts.next());
+    os == Some(image.os.as_str()) && arch.is_none_or(|x| x == image.architecture)
+}
+
+/// `image` as the daemon names it, with its tag or digest
+pub(super) fn daemon_reference(image: &ImageRef) -> String {
+    match (image.digest(), image.tag()) {
+        (Some(digest), _) => format!("{}@{}", image.name(), digest),
+        (None, tag) => format!("{}:{}", image.name(), tag.unwrap_or("latest")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::{is_platform, ImageMetadata, ImagePuller};
+    use crate::DockerConfig;
+
+    #[test]
+    fn platform() {
+        let image = ImageMetadata {
+            os: "linux".to_string(),
+            architecture: "arm64".to_string(),
+            ..Default::default()
+        };
+        assert!(is_platform(&image, "linux/arm64"));
+        assert!(is_platform(&image, "linux/arm64/v8"));
+        assert!(is_platform(&image, "linux"));
+        assert!(!is_platform(&image, "linux/amd64"));
+        assert!(!is_platform(&image, "windows/arm64"));
+    }
+
+    #[tokio::test]
+    async fn helper_credentials() {
+        let dir = std::env::temp_dir().join(format!("docker-pull-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let helper = dir.join("docker-credential-pull-stub");
+        fs::write(
+            &helper,
+            "#!/bin/sh\n\
+            read server\n\
+            echo \"{\\\"Username\\\":\\\"ci\\\",\\\"Secret\\\":\\\"$server\\\"}\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![dir.clone()];
+        paths.extend(std::env::split_paths(&path));
+        std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+
+        let config =
+            DockerConfig::parse(r#"{ "credHelpers": { "registry:5000": "pull-stub" } }"#).unwrap();
+        let puller =
+            ImagePuller::new("registry:5000/app:1".parse().unwrap()).with_docker_config(config);
+        let credentials = puller.credentials().await;
+        fs::remove_dir_all(&dir).unwrap();
+        let credentials = credentials.unwrap().unwrap();
+        assert_eq!(credentials.username.as_deref(), Some("ci"));
+        assert_eq!(credentials.password.as_deref(), Some("registry:5000"));
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use bollard::{auth::DockerCredentials, query_parameters::PushImageOptions, Docker};
+use color_eyre::eyre::Error;
+use dockerfiles::ImageRef;
+use futures::{future::ready, stream, Stream, TryStreamExt};
+
+use super::{BuildEvent, Image};
+use crate::DockerConfig;
+
+/// Times a push is tried before giving up, for flaky registries
+const PUSH_ATTEMPTS: u32 = 3;
+
+/// What the daemon says when pushing over HTTP to a registry it doesn't
+/// trust as insecure
+const HTTPS_ONLY: &str = "server gave HTTP response to HTTPS client";
+
+impl Image {
+    /// Add every one of `tags` to this image, see [Self::tag]
+    pub async fn tag_all<'a>(
+        &self,
+        docker: &Docker,
+        tags: impl IntoIterator<Item = &'a ImageRef>,
+    ) -> Result<(), Error> {
+        for tag in tags {
+            self.tag(docker, tag).await?;
+        }
+        Ok(())
+    }
+
+    /// Tag this image as `reference` and push it, with `credentials` or
+    /// those of the Docker config (see [DockerConfig::credentials]),
+    /// trying again if the registry fails
+    ///
+    /// Registries on `localhost` can be plain HTTP, others only if the
+    /// daemon lists them in its `insecure-registries`
+    pub async fn push(
+        &self,
+        docker: &Docker,
+        reference: &ImageRef,
+        credentials: Option<DockerCredentials>,
+    ) -> Result<(), Error> {
+        self.tag(docker, reference).await?;
+        let credentials = push_credentials(reference, credentials).await?;
+        let name = reference.to_string();
+        let mut attempt = 1;
+        loop {
+            let pushed = push_events(docker, reference, credentials.clone())
+                .try_for_each(|event| {
+                    event.trace(&name);
+                    ready(Ok(()))
+                })
+                .await;
+            match pushed {
+                Ok(()) => return Ok(()),
+                Err(e) if e.to_string().contains(HTTPS_ONLY) => {
+                    return Err(e.wrap_err(format!(
+                        "pushing {}, add its registry to the daemon's insecure-registries",
+                        reference
+                    )));
+                }
+                Err(e) if attempt < PUSH_ATTEMPTS && is_transient(&e) => {
+                    tracing::warn!("pushing {} failed, trying again: {}", reference, e);
+                    tokio::time::sleep(Duration::from_secs(attempt.into())).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.wrap_err(format!("pushing {}", reference))),
+            }
+        }
+    }
+
+    /// Tag this image as `reference` and push it once, yielding the
+    /// progress as [BuildEvent::Push]es like [crate::ImagePuller::pull_stream]
+    /// does, see [Self::push]
+    pub fn push_stream<'a>(
+        &'a self,
+        docker: &'a Docker,
+        reference: &'a ImageRef,
+        credentials: Option<DockerCredentials>,
+    ) -> impl Stream<Item = Result<BuildEvent, Error>> + 'a {
+        stream::once(async move {
+            self.tag(docker, reference).await?;
+            let credentials = push_credentials(reference, credentials).await?;
+            Ok::<_, Error>(push_events(docker, reference, credentials))
+        })
+        .try_flatten()
+    }
+}
+
+/// `credentials`, or those of the Docker config for `reference`
+async fn push_credentials(
+    reference: &ImageRef,
+    credentials: Option<DockerCredentials>,
+) -> Result<Option<DockerCredentials>, Error> {
+    match credentials {
+        Some(credentials) => Ok(Some(credentials)),
+        None => {
+            DockerConfig::load()?
+                .credentials(reference.registry())
+                .await
+        }
+    }
+}
+
+/// Push the already tagged `reference`, e.g. `Pushing` 1.2MB of 5MB
+fn push_events(
+    docker: &Docker,
+    reference: &ImageRef,
+    credentials: Option<DockerCredentials>,
+) -> impl Stream<Item = Result<BuildEvent, Error>> {
+    let opts = PushImageOptions {
+        tag: Some(reference.tag().unwrap_or("latest").to_string()),
+        ..Default::default()
+    };
+    docker
+        .push_image(&reference.name(), Some(opts), credentials)
+        .err_into::<Error>()
+        .try_filter_map(|info| {
+            ready(Ok(info.status.map(|status| {
+                let progress = info.progress_detail.unwrap_or_default();
+                BuildEvent::Push {
+                    status,
+                    current: progress.current,
+                    total: progress.total,
+                }
+            })))
+        })
+}
+
+/// Whether pushing again may work, i.e. the registry didn't refuse it
+fn is_transient(e: &Error) -> bool {
+    let message = e.to_string().to_lowercase();
+    ![
+        "unauthorized",
+        "denied",
+        "authentication required",
+        "not found",
+    ]
+    .iter()
+    .any(|x| message.contains(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::is_transient;
+
+    #[test]
+    fn transient_push_errors() {
+        assert!(is_transient(&eyre!("received unexpected EOF")));
+        assert!(is_transient(&eyre!("503 Service Unavailable")));
+        assert!(!is_transient(&eyre!(
+            "unauthorized: authentication required"
+        )));
+        assert!(!is_transient(&eyre!(
+            "denied: requested access to the resource is denied"
+        )));
+    }
+}
@@ -1,12 +1,16 @@
 #![feature(try_blocks)]
+mod auth;
 mod bootstrap;
 mod container;
+mod context;
 mod image;
 mod network;
 mod utils;
 
+pub use auth::*;
 pub use bollard::Docker;
 pub use bootstrap::*;
 pub use container::*;
+pub use context::*;
 pub use image::*;
 pub use network::*;
@@ -1,8 +1,8 @@
 mod dialog;
 
-use std::borrow::Cow;
+use std::cell::RefCell;
 
-use bollard::{network::CreateNetworkOptions, Docker};
+use bollard::{models::NetworkCreateRequest, Docker};
 use color_eyre::{
     eyre::Error,
     owo_colors::{OwoColorize, Style},
@@ -14,20 +14,19 @@ use futures::{
     FutureExt, StreamExt, TryFutureExt, TryStreamExt,
 };
 
-use crate::{utils::ctrl_c, Container, ContainerBuilder};
+use crate::{utils::ctrl_c, Container, ContainerBuilder, DockerFileSource};
 
 pub struct ContainerNetworkBuilder<'a, T> {
-    opts: CreateNetworkOptions<&'a str>,
+    opts: NetworkCreateRequest,
     containers: Vec<ContainerBuilder<'a, T>>,
 }
 
 impl<'a, T> ContainerNetworkBuilder<'a, T> {
     pub fn new(name: &'a str) -> Self {
         Self {
-            opts: CreateNetworkOptions {
-                name,
-                check_duplicate: true,
-                driver: "bridge",
+            opts: NetworkCreateRequest {
+                name: name.to_string(),
+                driver: Some("bridge".to_string()),
                 // enable_ipv6: true,
                 ..Default::default()
             },
@@ -49,21 +48,36 @@ impl<'a, T> ContainerNetworkBuilder<'a, T> {
 
     pub async fn build<'b>(self, docker: &Docker) -> Result<ContainerNetwork, Error>
     where
-        T: Into<Cow<'b, str>>,
+        T: DockerFileSource<'b>,
     {
         // 1. create network
         let network = docker.create_network(self.opts).await?;
-        network.warning.inspect(|x| eprintln!("{}", x));
-        let network_id = network.id.unwrap();
+        if !network.warning.is_empty() {
+            eprintln!("{}", network.warning);
+        }
+        let network_id = network.id;
 
-        // 2. create containers
+        // 2. create containers, showing their builds like their logs
+        let logger = RefCell::new(Dialogger::default());
         let containers: Vec<_> = self
             .containers
             .into_iter()
-            .map(|container| container.with_net(&network_id).build(docker))
+            .map(|container| {
+                let name = container.name();
+                let logger = &logger;
+                container
+                    .with_net(&network_id)
+                    .build_with(docker, move |_, event| {
+                        if event.is_rendered() {
+                            logger
+                                .replace_with(|x| std::mem::take(x).log(name, &event.to_string()));
+                        }
+                    })
+            })
             .collect::<FuturesUnordered<_>>()
             .try_collect()
             .await?;
+        logger.into_inner().print_end();
 
         Ok(ContainerNetwork {
             id: network_id,
@@ -140,7 +154,7 @@ impl ContainerNetwork {
         .try_fold(Dialogger::default(), |logger, (c, l)| async move {
             match l.to_string().trim_matches('\n') {
                 "" => Ok(logger),
-                l => Ok(logger.log(c, l.to_string().trim_matches('\n'))),
+                l => Ok(logger.log(c.name(), l.to_string().trim_matches('\n'))),
             }
         })
         .await?
@@ -3,17 +3,16 @@ use std::collections::HashMap;
 use color_eyre::owo_colors::{OwoColorize, Rgb, Style};
 use rand::{rngs::ThreadRng, Rng};
 
-use crate::Container;
-
+/// Prints lines grouped by the container (name) they come from
 #[derive(Default)]
 pub(super) struct Dialogger<'a> {
     dia_len: usize,
-    current_id: Option<&'a Container>,
+    current_id: Option<&'a str>,
     styler: Styler<'a>,
 }
 
 impl<'a> Dialogger<'a> {
-    pub fn with_id(self, id: &'a Container) -> Self {
+    pub fn with_id(self, id: &'a str) -> Self {
         let dia_len = if self.current_id == Some(id) {
             self.dia_len + 1
         } else {
@@ -27,7 +26,7 @@ impl<'a> Dialogger<'a> {
         }
     }
 
-    pub fn log(mut self, id: &'a Container, msg: &str) -> Self {
+    pub fn log(mut self, id: &'a str, msg: &str) -> Self {
         match self.current_id {
             Some(x) if x == id => self.print_mid(msg),
             Some(_) => {
@@ -45,8 +44,8 @@ impl<'a> Dialogger<'a> {
         }
     }
 
-    fn print_start(&mut self, id: &'a Container, msg: &str) {
-        println!("{:<20}{}", id.name().style(self.get_style(id)), msg)
+    fn print_start(&mut self, id: &'a str, msg: &str) {
+        println!("{:<20}{}", id.style(self.styler.get(id)), msg)
     }
 
     fn print_mid(&mut self, msg: &str) {
@@ -55,13 +54,9 @@ impl<'a> Dialogger<'a> {
 
     fn current_style(&mut self) -> Style {
         self.current_id
-            .map(|id| self.styler.get(id.name()))
+            .map(|id| self.styler.get(id))
             .unwrap_or_default()
     }
-
-    fn get_style(&mut self, id: &'a Container) -> Style {
-        self.styler.get(id.name())
-    }
 }
 
 #[derive(Default)]
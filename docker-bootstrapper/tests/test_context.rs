@@ -0,0 +1,157 @@
+use std::{fs, io::Read, os::unix::fs::PermissionsExt, path::PathBuf};
+
+use docker_bootstrapper::{BuildContext, DockerIgnore};
+use rand::{distributions::Alphanumeric, Rng};
+
+fn temp_dir() -> PathBuf {
+    let id: String = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+    std::env::temp_dir().join(format!("docker-bootstrapper-context-{}", id))
+}
+
+/// Path, mode and link target of every entry
+fn entries(tar: &[u8]) -> Vec<(String, u32, Option<String>)> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tar));
+    archive
+        .entries()
+        .unwrap()
+        .map(|x| {
+            let x = x.unwrap();
+            let link = x.link_name().unwrap().map(|x| x.display().to_string());
+            let path = x.path().unwrap().display().to_string();
+            (path, x.header().mode().unwrap() & 0o7777, link)
+        })
+        .collect()
+}
+
+#[test]
+fn dockerignore() {
+    let ignore = DockerIgnore::parse(
+        "# build output\n\
+        target\n\
+        /docs/*.md\n\
+        **/*.log\n\
+        !important.log\n\
+        tmp[0-9]\n",
+    );
+    assert!(ignore.is_excluded("target"));
+    assert!(ignore.is_excluded("target/debug/app"));
+    assert!(!ignore.is_excluded("src/target"));
+    assert!(ignore.is_excluded("docs/a.md"));
+    assert!(!ignore.is_excluded("docs/sub/a.md"));
+    assert!(ignore.is_excluded("a.log"));
+    assert!(ignore.is_excluded("src/deep/b.log"));
+    assert!(!ignore.is_excluded("important.log"));
+    assert!(ignore.is_excluded("tmp1"));
+    assert!(!ignore.is_excluded("tmpx"));
+    assert!(ignore.has_exceptions());
+}
+
+#[test]
+fn context_tarball() -> std::io::Result<()> {
+    let dir = temp_dir();
+    fs::create_dir_all(dir.join("bin"))?;
+    fs::create_dir_all(dir.join("target/debug"))?;
+    fs::write(
+        dir.join(".dockerignore"),
+        "target\n!target/debug/app\n*.md\n",
+    )?;
+    fs::write(dir.join("Dockerfile"), "FROM scratch\n")?;
+    fs::write(dir.join("README.md"), "")?;
+    fs::write(dir.join("bin/run.sh"), "#!/bin/sh\n")?;
+    std::os::unix::fs::symlink("bin/run.sh", dir.join("run"))?;
+    fs::write(dir.join("target/debug/app"), "")?;
+    fs::write(dir.join("target/debug/deps"), "")?;
+    fs::write(dir.join("config.toml"), "from disk")?;
+    // independent of the umask
+    for (path, mode) in [
+        (".dockerignore", 0o644),
+        ("bin", 0o755),
+        ("bin/run.sh", 0o755),
+        ("target/debug/app", 0o644),
+    ] {
+        fs::set_permissions(dir.join(path), fs::Permissions::from_mode(mode))?;
+    }
+
+    let context = BuildContext::new()
+        .with_dir(&dir)
+        .with_file("./config.toml", "generated");
+    let mut tar = Vec::new();
+    let written = context.write_tar("FROM alpine:3.19\n", &mut tar);
+    fs::remove_dir_all(&dir)?;
+    written?;
+
+    assert_eq!(
+        entries(&tar),
+        [
+            (".dockerignore".to_string(), 0o644, None),
+            ("Dockerfile".to_string(), 0o755, None),
+            ("bin".to_string(), 0o755, None),
+            ("bin/run.sh".to_string(), 0o755, None),
+            ("config.toml".to_string(), 0o644, None),
+            ("run".to_string(), 0o777, Some("bin/run.sh".to_string())),
+            ("target/debug/app".to_string(), 0o644, None),
+        ]
+    );
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&tar[..]));
+    let mut contents = String::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.ends_with("config.toml") {
+            entry.read_to_string(&mut contents)?;
+        }
+    }
+    assert_eq!(contents, "generated");
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "invalid path in build context")]
+fn context_file_outside() {
+    BuildContext::new().with_file("../secret", "");
+}
+
+#[test]
+#[should_panic(expected = "is the one being built")]
+fn context_file_dockerfile() {
+    BuildContext::new().with_file("./Dockerfile", "FROM alpine\n");
+}
+
+#[test]
+fn context_reproducible() -> std::io::Result<()> {
+    let dir = temp_dir();
+    fs::create_dir_all(dir.join("src"))?;
+    fs::write(dir.join("src/main.rs"), "fn main() {}\n")?;
+    let context = BuildContext::new().with_dir(&dir);
+    let mut first = Vec::new();
+    let first_digest = context.write_tar("FROM rust\n", &mut first);
+    // touched files make the same tarball
+    let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+    let touched = fs::File::options()
+        .write(true)
+        .open(dir.join("src/main.rs"))
+        .and_then(|x| x.set_modified(modified));
+    let mut second = Vec::new();
+    let second_digest = context.write_tar("FROM rust\n", &mut second);
+    let mut other = Vec::new();
+    let other_digest = context.write_tar("FROM rust:1.80\n", &mut other);
+    let unwritten_digest = context.digest("FROM rust\n");
+    fs::remove_dir_all(&dir)?;
+    touched?;
+
+    assert_eq!(first, second);
+    let first_digest = first_digest?;
+    assert_eq!(first_digest, second_digest?);
+    assert_eq!(first_digest, unwritten_digest?);
+    assert_ne!(second, other);
+    assert!(other_digest?.starts_with("sha256:"));
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&first[..]));
+    for entry in archive.entries()? {
+        let header = entry?.header().clone();
+        assert_eq!((header.uid()?, header.gid()?), (0, 0));
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use bollard::{
+    models::{ContainerCreateBody, HostConfig, PortBinding},
+    query_parameters::{
+        CreateContainerOptions, RemoveContainerOptions, RemoveImageOptions, StartContainerOptions,
+    },
+    Docker,
+};
+use docker_bootstrapper::{BuildEvent, Image, ImageBuilder, ImagePuller, PullPolicy};
+use dockerfiles::{DockerFile, Env, Expose, From, ImageRef, Label, Run, WorkDir};
+use futures::TryStreamExt;
+
+#[tokio::test]
+async fn image_pull() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+    let docker = Docker::connect_with_defaults()?;
+    let alpine: ImageRef = "alpine:3.19".parse()?;
+    let image = Image::pull(&docker, &alpine).await?;
+    assert!(image.id.starts_with("sha256:"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_validation() -> color_eyre::Result<()> {
+    // validation fails before the daemon is ever contacted
+    let docker = Docker::connect_with_http_defaults()?;
+    let dockerfile =
+        DockerFile::new(From::image("alpine").with_tag("3.19")).then(WorkDir::new("relative"));
+    let err = ImageBuilder::new(&dockerfile)
+        .with_validation(true)
+        .build(&docker)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("DL3000"), "{}", err);
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_pull_policy() -> color_eyre::Result<()> {
+    let docker = Docker::connect_with_defaults()?;
+    let alpine: ImageRef = "alpine:3.19".parse()?;
+    let pulled = ImagePuller::new(alpine.clone()).pull(&docker).await?;
+    let present = ImagePuller::new(alpine)
+        .with_policy(PullPolicy::Never)
+        .pull(&docker)
+        .await?;
+    assert_eq!(pulled.id, present.id);
+
+    let missing = ImagePuller::new("localhost:5999/missing:none".parse()?)
+        .with_policy(PullPolicy::Never)
+        .pull(&docker)
+        .await
+        .unwrap_err();
+    assert!(
+        missing.to_string().contains("pull policy is never"),
+        "{}",
+        missing
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_push() -> color_eyre::Result<()> {
+    let docker = Docker::connect_with_defaults()?;
+    ImagePuller::new("registry:2".parse()?)
+        .pull(&docker)
+        .await?;
+    // plain HTTP, which the daemon allows on localhost
+    let config = ContainerCreateBody {
+        image: Some("registry:2".to_string()),
+        host_config: Some(HostConfig {
+            port_bindings: Some(HashMap::from([(
+                "5000/tcp".to_string(),
+                Some(vec![PortBinding {
+                    host_ip: Some("127.0.0.1".to_string()),
+                    host_port: Some("5055".to_string()),
+                }]),
+            )])),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let opts = CreateContainerOptions {
+        name: Some("docker-bootstrapper-registry".to_string()),
+        ..Default::default()
+    };
+    let registry = docker.create_container(Some(opts), config).await?.id;
+    let pushed: color_eyre::Result<_> = async {
+        docker
+            .start_container(&registry, None::<StartContainerOptions>)
+            .await?;
+        let dockerfile =
+            DockerFile::new(From::image("alpine").with_tag("3.19")).then(Label::new("stage", "ci"));
+        let image = ImageBuilder::new(&dockerfile).build(&docker).await?;
+        let tags: Vec<ImageRef> = vec!["shared/app:ci".parse()?, "shared/app:latest".parse()?];
+        image.tag_all(&docker, &tags).await?;
+        let remote: ImageRef = "localhost:5055/shared/app:ci".parse()?;
+        image.push(&docker, &remote, None).await?;
+        let latest: ImageRef = "localhost:5055/shared/app:latest".parse()?;
+        let events: Vec<_> = image
+            .push_stream(&docker, &latest, None)
+            .try_collect()
+            .await?;
+        assert!(events.iter().any(|x| matches!(x, BuildEvent::Push { .. })));
+        docker
+            .remove_image(&remote.to_string(), None::<RemoveImageOptions>, None)
+            .await?;
+        let pulled = ImagePuller::new(remote)
+            .with_policy(PullPolicy::Always)
+            .pull(&docker)
+            .await?;
+        Ok((image, pulled))
+    }
+    .await;
+    let opts = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    docker.remove_container(&registry, Some(opts)).await?;
+    let (image, pulled) = pushed?;
+    assert_eq!(image.id, pulled.id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_save_load() -> color_eyre::Result<()> {
+    let docker = Docker::connect_with_defaults()?;
+    let alpine = ImagePuller::new("alpine:3.19".parse()?)
+        .pull(&docker)
+        .await?;
+    let tag: ImageRef = "docker-bootstrapper/saved:test".parse()?;
+    alpine.tag(&docker, &tag).await?;
+    let path = std::env::temp_dir().join(format!("docker-bootstrapper-{}.tar", std::process::id()));
+    Image::save(&docker, &tag.to_string(), &path).await?;
+    docker
+        .remove_image(&tag.to_string(), None::<RemoveImageOptions>, None)
+        .await?;
+
+    let loaded = Image::load(&docker, &path).await;
+    std::fs::remove_file(&path)?;
+    assert_eq!(loaded?[0].id, alpine.id);
+    assert!(Image::local(&docker, &tag.to_string()).await?.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn image_inspect() -> color_eyre::Result<()> {
+    let docker = Docker::connect_with_defaults()?;
+    let dockerfile = DockerFile::new(From::image("alpine").with_tag("3.19"))
+        .then(Env::new("MODE", "test"))
+        .then(Expose::tcp(8080))
+        .then(Label::new("suite", "inspect"))
+        .then(Run::new("head -c 1048576 /dev/zero > /payload"));
+    let image = ImageBuilder::new(&dockerfile).build(&docker).await?;
+
+    let metadata = image.inspect(&docker).await?;
+    assert_eq!(metadata.id, image.id);
+    assert!(metadata.size < 50 << 20, "{} bytes", metadata.size);
+    assert_eq!(metadata.env_var("MODE"), Some("test"));
+    assert_eq!(metadata.exposed_ports, ["8080/tcp"]);
+    assert_eq!(metadata.labels["suite"], "inspect");
+    assert_eq!(metadata.os, "linux");
+    assert_eq!(metadata.layers.len(), 2);
+
+    let history = image.history(&docker).await?;
+    let payload = history
+        .iter()
+        .find(|x| x.created_by.contains("/payload"))
+        .unwrap();
+    assert!(payload.size >= 1 << 20, "{} bytes", payload.size);
+    assert_eq!(history.iter().map(|x| x.size).sum::<u64>(), metadata.size);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::{collections::HashMap, io::Read};
+
+use docker_bootstrapper::OciImageBuilder;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// Contents of every entry by path
+fn entries(tar: &[u8]) -> HashMap<String, (u32, Vec<u8>)> {
+    let mut archive = tar::Archive::new(tar);
+    archive
+        .entries()
+        .unwrap()
+        .map(|x| {
+            let mut x = x.unwrap();
+            let mut contents = Vec::new();
+            x.read_to_end(&mut contents).unwrap();
+            let path = x.path().unwrap().display().to_string();
+            (path, (x.header().mode().unwrap(), contents))
+        })
+        .collect()
+}
+
+fn json(entries: &HashMap<String, (u32, Vec<u8>)>, path: &str) -> Value {
+    serde_json::from_slice(&entries[path].1).unwrap()
+}
+
+/// The blob a descriptor points to, checking its digest and size
+fn blob<'a>(entries: &'a HashMap<String, (u32, Vec<u8>)>, descriptor: &Value) -> &'a [u8] {
+    let digest = descriptor["digest"].as_str().unwrap();
+    let contents = &entries[&format!("blobs/{}", digest.replace(':', "/"))].1;
+    assert_eq!(
+        digest,
+        format!("sha256:{}", hex::encode(Sha256::digest(contents)))
+    );
+    assert_eq!(descriptor["size"], contents.len());
+    contents
+}
+
+#[test]
+fn oci_layout() {
+    let builder = OciImageBuilder::new()
+        .with_file("/usr/local/bin/app", "\x7fELF", 0o755)
+        .with_file("/etc/app/config.toml", "port = 80", 0o644)
+        .with_entrypoint(["/usr/local/bin/app"])
+        .with_env("RUST_LOG", "debug")
+        .with_architecture("amd64")
+        .with_tag("bootstrap/app:test".parse().unwrap());
+    let mut tar = Vec::new();
+    let id = builder.write_tar(&mut tar).unwrap();
+    let entries = entries(&tar);
+
+    assert_eq!(
+        entries["oci-layout"].1,
+        br#"{"imageLayoutVersion":"1.0.0"}"#
+    );
+    let index = json(&entries, "index.json");
+    let descriptor = &index["manifests"][0];
+    assert_eq!(
+        descriptor["annotations"]["io.containerd.image.name"],
+        "bootstrap/app:test"
+    );
+    let manifest: Value = serde_json::from_slice(blob(&entries, descriptor)).unwrap();
+    let config: Value = serde_json::from_slice(blob(&entries, &manifest["config"])).unwrap();
+    assert_eq!(manifest["config"]["digest"], id);
+    assert_eq!(config["architecture"], "amd64");
+    assert_eq!(config["config"]["Entrypoint"][0], "/usr/local/bin/app");
+    assert_eq!(config["config"]["Env"][0], "RUST_LOG=debug");
+    assert_eq!(
+        config["rootfs"]["diff_ids"][0],
+        manifest["layers"][0]["digest"]
+    );
+
+    let docker_manifest = json(&entries, "manifest.json");
+    assert_eq!(docker_manifest[0]["RepoTags"][0], "bootstrap/app:test");
+    assert_eq!(
+        docker_manifest[0]["Config"],
+        format!("blobs/{}", id.replace(':', "/"))
+    );
+
+    let layer = self::entries(blob(&entries, &manifest["layers"][0]));
+    let mut paths: Vec<_> = layer
+        .iter()
+        .map(|(path, (mode, _))| (path.as_str(), *mode))
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            ("etc/", 0o755),
+            ("etc/app/", 0o755),
+            ("etc/app/config.toml", 0o644),
+            ("usr/", 0o755),
+            ("usr/local/", 0o755),
+            ("usr/local/bin/", 0o755),
+            ("usr/local/bin/app", 0o755),
+        ]
+    );
+    assert_eq!(layer["usr/local/bin/app"].1, b"\x7fELF");
+
+    // the same files make the same image
+    let mut again = Vec::new();
+    assert_eq!(builder.write_tar(&mut again).unwrap(), id);
+    assert_eq!(tar, again);
+}
+
+#[test]
+#[should_panic(expected = "invalid path in image")]
+fn oci_relative_path() {
+    OciImageBuilder::new().with_file("app", "", 0o755);
+}
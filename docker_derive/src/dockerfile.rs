@@ -0,0 +1,255 @@
+use docker_keywords::flags_of;
+use proc_macro::{Delimiter, Group, Span, TokenStream, TokenTree};
+use quote::quote;
+
+/// Instructions whose arguments may be a JSON array (exec form)
+const JSON_FORMS: &[&str] = &["RUN", "CMD", "ENTRYPOINT", "COPY", "ADD", "VOLUME", "SHELL"];
+
+fn error(span: Span, message: impl std::fmt::Display) -> syn::Error {
+    syn::Error::new(span.into(), message)
+}
+
+/// Whether there is no whitespace between `a` and `b` in the source
+fn adjacent(a: Span, b: Span) -> bool {
+    let (a, b) = (a.end(), b.start());
+    a.line() == b.line() && a.column() == b.column()
+}
+
+/// The Dockerfile text split at every interpolated `{expr}`, which are
+/// put back in when the macro's code runs
+#[derive(Default)]
+struct Text {
+    /// Text up to each `{expr}`, and after the last one
+    texts: Vec<String>,
+    current: String,
+    /// `(expr.to_string(), whether it is a JSON array element)`
+    values: Vec<proc_macro2::TokenStream>,
+}
+
+impl Text {
+    fn push(&mut self, text: &str) {
+        self.current += text;
+    }
+
+    fn interpolate(&mut self, expr: TokenStream, json: bool) {
+        let expr = proc_macro2::TokenStream::from(expr);
+        self.texts.push(std::mem::take(&mut self.current));
+        self.values
+            .push(quote! { (::std::string::ToString::to_string(&(#expr)), #json) });
+    }
+
+    /// Append `tokens`, separated as they were in the source
+    fn tokens(&mut self, tokens: &[TokenTree], mut last: Option<Span>) {
+        for (i, token) in tokens.iter().enumerate() {
+            if last.is_some_and(|x| !adjacent(x, token.span())) {
+                self.current.push(' ');
+            }
+            last = Some(token.span());
+            match token {
+                // `${VAR}` is left for Docker to substitute
+                TokenTree::Group(group)
+                    if group.delimiter() == Delimiter::Brace
+                        && !(i > 0 && is_dollar(&tokens[i - 1], group.span())) =>
+                {
+                    self.interpolate(group.stream(), false)
+                }
+                TokenTree::Group(group) => self.group(group),
+                token => self.push(&token.to_string()),
+            }
+        }
+    }
+
+    fn group(&mut self, group: &Group) {
+        let (open, close) = match group.delimiter() {
+            Delimiter::Parenthesis => ("(", ")"),
+            Delimiter::Bracket => ("[", "]"),
+            Delimiter::Brace => ("{", "}"),
+            Delimiter::None => ("", ""),
+        };
+        let tokens: Vec<_> = group.stream().into_iter().collect();
+        self.push(open);
+        self.tokens(&tokens, Some(group.span_open()));
+        if tokens
+            .last()
+            .is_some_and(|x| !adjacent(x.span(), group.span_close()))
+        {
+            self.current.push(' ');
+        }
+        self.push(close);
+    }
+
+    /// An exec form `["sh", "-c", {expr}]`, already checked by [check_json]
+    fn json(&mut self, group: &Group) {
+        self.current.push('[');
+        for (i, token) in group.stream().into_iter().enumerate() {
+            match token {
+                TokenTree::Punct(_) => self.current.push(','),
+                _ if i > 0 => self.current.push(' '),
+                _ => {}
+            }
+            match token {
+                TokenTree::Group(x) => self.interpolate(x.stream(), true),
+                TokenTree::Punct(_) => {}
+                x => self.push(&x.to_string()),
+            }
+        }
+        self.current.push(']');
+    }
+}
+
+fn is_dollar(token: &TokenTree, next: Span) -> bool {
+    matches!(token, TokenTree::Punct(x) if x.as_char() == '$' && adjacent(x.span(), next))
+}
+
+/// Elements must be string literals or `{expr}`s, separated by commas
+fn check_json(group: &Group) -> syn::Result<()> {
+    let tokens: Vec<_> = group.stream().into_iter().collect();
+    for (i, token) in tokens.iter().enumerate() {
+        let ok = match (i % 2, token) {
+            (0, TokenTree::Literal(x)) => {
+                let x = x.to_string();
+                x.starts_with('"') && x.ends_with('"')
+            }
+            (0, TokenTree::Group(x)) => x.delimiter() == Delimiter::Brace,
+            (1, TokenTree::Punct(x)) => x.as_char() == ',' && i + 1 < tokens.len(),
+            _ => false,
+        };
+        if !ok {
+            let message = match i % 2 {
+                0 => "expected a string literal or `{expr}` in the JSON array",
+                _ => "expected `,` between JSON array elements",
+            };
+            return Err(error(token.span(), message));
+        }
+    }
+    match tokens.is_empty() {
+        true => Err(error(group.span(), "empty JSON array")),
+        false => Ok(()),
+    }
+}
+
+/// Leading `--name[=value]` flags as `(name, span, tokens used)`
+fn flags(args: &[TokenTree]) -> Vec<(String, Span, usize)> {
+    let mut flags = Vec::new();
+    let mut i = 0;
+    let is_dash =
+        |x: Option<&TokenTree>| matches!(x, Some(TokenTree::Punct(x)) if x.as_char() == '-');
+    while is_dash(args.get(i)) && is_dash(args.get(i + 1)) {
+        let start = args[i].span();
+        let mut name = String::new();
+        let mut j = i + 2;
+        // the whole flag is one word, e.g. `--start-period=1s`
+        while let Some(x) = args
+            .get(j)
+            .filter(|x| adjacent(args[j - 1].span(), x.span()))
+        {
+            if name.contains('=') || !matches!(x, TokenTree::Punct(p) if p.as_char() == '=') {
+                name += &x.to_string();
+            } else {
+                name.push('=');
+            }
+            j += 1;
+        }
+        let name = name.split('=').next().unwrap_or_default().to_string();
+        flags.push((name, start, j - i));
+        i = j;
+    }
+    flags
+}
+
+pub(crate) fn expand(input: TokenStream) -> syn::Result<proc_macro2::TokenStream> {
+    let tokens: Vec<_> = input.into_iter().collect();
+    let instructions = tokens
+        .split(|x| matches!(x, TokenTree::Punct(x) if x.as_char() == ';'))
+        .filter(|x| !x.is_empty());
+
+    let mut text = Text::default();
+    let mut positions = Vec::new();
+    let mut has_from = false;
+    for instruction in instructions {
+        let TokenTree::Ident(keyword) = &instruction[0] else {
+            return Err(error(instruction[0].span(), "expected an instruction"));
+        };
+        let name = keyword.to_string();
+        let Some(known_flags) = flags_of(&name) else {
+            return Err(error(
+                keyword.span(),
+                format!("unknown instruction {}", name),
+            ));
+        };
+        if !has_from && name != "FROM" && name != "ARG" {
+            return Err(error(
+                keyword.span(),
+                "expected FROM before any instruction",
+            ));
+        }
+        has_from |= name == "FROM";
+        let args = &instruction[1..];
+        if args.is_empty() {
+            return Err(error(keyword.span(), format!("{} needs arguments", name)));
+        }
+
+        positions.push({
+            let span = keyword.span();
+            let (line, column) = (span.line(), span.column());
+            quote! { (#line, #column) }
+        });
+        text.push(&name);
+        let mut used = 0;
+        for (flag, span, len) in flags(args) {
+            if !known_flags.contains(&flag.as_str()) {
+                return Err(error(span, format!("unknown flag --{} for {}", flag, name)));
+            }
+            text.current.push(' ');
+            text.tokens(&args[used..used + len], None);
+            used += len;
+        }
+        let rest = &args[used..];
+        match rest.first() {
+            Some(TokenTree::Group(x))
+                if x.delimiter() == Delimiter::Bracket && JSON_FORMS.contains(&name.as_str()) =>
+            {
+                check_json(x)?;
+                if rest.len() > 1 {
+                    return Err(error(rest[1].span(), "unexpected tokens after JSON array"));
+                }
+                text.current.push(' ');
+                text.json(x);
+            }
+            _ if name == "SHELL" => {
+                return Err(error(keyword.span(), "SHELL needs a JSON array"));
+            }
+            None => return Err(error(keyword.span(), format!("{} needs arguments", name))),
+            Some(_) => {
+                text.current.push(' ');
+                text.tokens(rest, None);
+            }
+        }
+        text.current.push('\n');
+    }
+    if !has_from {
+        return Err(syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "expected FROM before any instruction",
+        ));
+    }
+
+    let Text {
+        mut texts,
+        current,
+        values,
+    } = text;
+    if values.is_empty() {
+        return Ok(quote! {
+            ::dockerfiles::__derive::literal_dockerfile(#current, &[#(#positions),*])
+        });
+    }
+    texts.push(current);
+    Ok(quote! {
+        ::dockerfiles::__derive::dockerfile(
+            &[#(#texts),*],
+            ::std::vec![#(#values),*],
+            &[#(#positions),*],
+        )
+    })
+}
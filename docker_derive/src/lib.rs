@@ -1,19 +1,218 @@
 #![feature(proc_macro_quote)]
 
+mod dockerfile;
+
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::DeriveInput;
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Field, Fields, LitStr, Type};
+
+/// A `dockerfiles::DockerFile` from Dockerfile instructions separated by
+/// semicolons, checking instructions, flags and JSON arrays at compile time.
+/// `{expr}` interpolates a Rust expression (`${VAR}` is left to Docker),
+/// including as an element of a JSON array:
+/// `dockerfile! { FROM alpine:3.19; RUN apk add {pkg}; ENTRYPOINT ["sh"] }`
+///
+/// Without `{expr}`s it evaluates to a `DockerFile`, panicking on the
+/// arguments the parser rejects but the macro can't check, e.g. an invalid
+/// `--chmod`. With them it evaluates to a `Result<DockerFile, ParseError>`:
+/// the values are only known at runtime, and one that isn't valid where it
+/// is interpolated, or that spans lines and would add instructions, is an
+/// error with the line and column of its instruction in the macro call.
+///
+/// Tokens are separated by whitespace like they are in the macro call, but
+/// everything must be valid Rust tokens: use double quotes for shell
+/// strings and avoid backslashes.
+#[proc_macro]
+pub fn dockerfile(input: TokenStream) -> TokenStream {
+    dockerfile::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
 
-#[proc_macro_derive(Instruction)]
+/// Implements `dockerfiles::Instruction`, the keyword defaults to the
+/// uppercased struct name. Implements `Display` too when the struct has an
+/// `#[instruction(keyword = "...")]`, fields are then rendered in order
+/// after the keyword, flags first:
+/// - `#[instruction(flag = "chown")]` renders `--chown=value`, skipped
+///   when an `Option` is `None`, or `--link` for a `bool` that is `true`
+/// - `#[instruction(json_array)]` renders a list as `["a", "b"]`
+/// - `#[instruction(skip)]` doesn't render the field
+/// - anything else is rendered with its `Display`, lists space separated
+#[proc_macro_derive(Instruction, attributes(instruction))]
 pub fn instr_macro_derive(input: TokenStream) -> TokenStream {
-    let s: DeriveInput = syn::parse(input).unwrap();
+    let s = parse_macro_input!(input as DeriveInput);
     impl_instr(&s)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
 }
 
-fn impl_instr(ast: &DeriveInput) -> TokenStream {
+fn impl_instr(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
     let name = &ast.ident;
-    let tokens = quote! {
-        impl Instruction for #name {}
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let keyword = keyword(ast)?;
+    let keyword_str = match &keyword {
+        Some(x) => x.value(),
+        None => name.to_string().to_uppercase(),
+    };
+    let display = match keyword {
+        Some(keyword) => {
+            let body = display_body(ast, &keyword)?;
+            quote! {
+                impl #impl_generics ::std::fmt::Display for #name #ty_generics #where_clause {
+                    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                        #body
+                    }
+                }
+            }
+        }
+        None => quote! {},
+    };
+    Ok(quote! {
+        impl #impl_generics ::dockerfiles::Instruction for #name #ty_generics #where_clause {
+            fn keyword(&self) -> &'static str {
+                #keyword_str
+            }
+
+            fn as_any(&self) -> &dyn ::std::any::Any {
+                self
+            }
+
+            fn as_any_mut(&mut self) -> &mut dyn ::std::any::Any {
+                self
+            }
+        }
+        #display
+    })
+}
+
+fn keyword(ast: &DeriveInput) -> syn::Result<Option<LitStr>> {
+    let mut keyword = None;
+    for attr in ast
+        .attrs
+        .iter()
+        .filter(|x| x.path().is_ident("instruction"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("keyword") {
+                return Err(meta.error("expected `keyword = \"...\"`"));
+            }
+            let value: LitStr = meta.value()?.parse()?;
+            let valid =
+                !value.value().is_empty() && value.value().chars().all(|c| c.is_ascii_uppercase());
+            if !valid {
+                return Err(syn::Error::new(
+                    value.span(),
+                    "keyword must be an uppercase word such as \"COPY\"",
+                ));
+            }
+            keyword = Some(value);
+            Ok(())
+        })?;
+    }
+    Ok(keyword)
+}
+
+enum Render {
+    Flag(LitStr),
+    JsonArray,
+    Plain,
+    Skip,
+}
+
+fn render(field: &Field) -> syn::Result<Render> {
+    let mut render = Render::Plain;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|x| x.path().is_ident("instruction"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if !matches!(render, Render::Plain) {
+                return Err(meta.error("only one of `flag`, `json_array` and `skip` is allowed"));
+            }
+            render = if meta.path.is_ident("flag") {
+                Render::Flag(meta.value()?.parse()?)
+            } else if meta.path.is_ident("json_array") {
+                Render::JsonArray
+            } else if meta.path.is_ident("skip") {
+                Render::Skip
+            } else {
+                return Err(meta.error("expected `flag = \"...\"`, `json_array` or `skip`"));
+            };
+            Ok(())
+        })?;
+    }
+    Ok(render)
+}
+
+/// Last path segment of the field's type, e.g. `Option` for `Option<String>`
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(x) => x.path.segments.last().map(|x| x.ident.to_string()),
+        _ => None,
+    }
+}
+
+fn display_body(ast: &DeriveInput, keyword: &LitStr) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &ast.data {
+        Data::Struct(x) => &x.fields,
+        _ => {
+            return Err(syn::Error::new(
+                ast.ident.span(),
+                "`#[instruction(keyword)]` is only supported on structs",
+            ))
+        }
     };
-    tokens.into()
+    if let Fields::Unnamed(x) = fields {
+        return Err(syn::Error::new(
+            x.span(),
+            "`#[instruction(keyword)]` needs named fields",
+        ));
+    }
+
+    let mut flags = Vec::new();
+    let mut args = Vec::new();
+    for field in fields {
+        let ident = &field.ident;
+        let ty = type_name(&field.ty);
+        match render(field)? {
+            Render::Flag(flag) => flags.push(match ty.as_deref() {
+                Some("Option") => quote! {
+                    if let Some(x) = &self.#ident {
+                        write!(f, " --{}={}", #flag, x)?;
+                    }
+                },
+                Some("bool") => quote! {
+                    if self.#ident {
+                        write!(f, " --{}", #flag)?;
+                    }
+                },
+                _ => quote! { write!(f, " --{}={}", #flag, self.#ident)?; },
+            }),
+            Render::JsonArray => args.push(quote! {
+                write!(f, " ")?;
+                ::dockerfiles::__derive::fmt_json_array(f, &self.#ident)?;
+            }),
+            Render::Plain => args.push(match ty.as_deref() {
+                Some("Option") => quote! {
+                    if let Some(x) = &self.#ident {
+                        write!(f, " {}", x)?;
+                    }
+                },
+                Some("Vec") => quote! {
+                    for x in &self.#ident {
+                        write!(f, " {}", x)?;
+                    }
+                },
+                _ => quote! { write!(f, " {}", self.#ident)?; },
+            }),
+            Render::Skip => {}
+        }
+    }
+    Ok(quote! {
+        write!(f, #keyword)?;
+        #(#flags)*
+        #(#args)*
+        Ok(())
+    })
 }
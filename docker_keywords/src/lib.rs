@@ -0,0 +1,41 @@
+//! The Dockerfile instructions and their flags, shared by the parser in
+//! `dockerfiles` and the `dockerfile!` macro in `docker_derive`
+
+/// Instructions with the flags each of them takes
+pub const INSTRUCTIONS: &[(&str, &[&str])] = &[
+    ("FROM", &["platform"]),
+    ("RUN", &["mount", "network"]),
+    ("CMD", &[]),
+    ("ENTRYPOINT", &[]),
+    ("COPY", &["from", "chown", "chmod", "link", "parents"]),
+    ("ADD", &["chown", "chmod", "link"]),
+    ("VOLUME", &[]),
+    ("SHELL", &[]),
+    ("WORKDIR", &[]),
+    ("STOPSIGNAL", &[]),
+    ("USER", &[]),
+    (
+        "HEALTHCHECK",
+        &[
+            "interval",
+            "timeout",
+            "start-period",
+            "start-interval",
+            "retries",
+        ],
+    ),
+    ("ONBUILD", &[]),
+    ("ENV", &[]),
+    ("LABEL", &[]),
+    ("ARG", &[]),
+    ("EXPOSE", &[]),
+];
+
+/// Flags of the instruction `keyword`, `None` if there is no such
+/// instruction
+pub fn flags_of(keyword: &str) -> Option<&'static [&'static str]> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(x, _)| *x == keyword)
+        .map(|(_, flags)| *flags)
+}
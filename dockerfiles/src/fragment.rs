@@ -0,0 +1,256 @@
+use itertools::Itertools;
+
+use crate::{DockerFile, ImageRef, Instruction, Run};
+
+/// Reusable instructions that can be added to a [DockerFile] with
+/// [DockerFile::then], every [Instruction] is one
+pub trait Fragment {
+    /// Add to the current (last) stage of `docker_file`
+    fn apply(self, docker_file: &mut DockerFile);
+}
+
+impl<I: Instruction> Fragment for I {
+    fn apply(self, docker_file: &mut DockerFile) {
+        docker_file.current_stage().instrs.push(Box::new(self));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Apk,
+    Apt,
+    Dnf,
+}
+
+impl PackageManager {
+    /// Guess from the image family, e.g. `python:3.12-alpine` uses apk
+    /// while `python:3.12` is Debian based
+    pub fn detect(image: &ImageRef) -> Option<Self> {
+        let name = image.repository().rsplit('/').next().unwrap_or_default();
+        if name == "alpine" || image.tag().is_some_and(|x| x.contains("alpine")) {
+            return Some(Self::Apk);
+        }
+        match name {
+            "debian" | "ubuntu" | "buildpack-deps" | "rust" | "python" | "node" | "golang"
+            | "ruby" | "php" | "perl" | "gcc" | "openjdk" | "eclipse-temurin" => Some(Self::Apt),
+            "fedora" | "centos" | "rockylinux" | "almalinux" | "amazonlinux" | "oraclelinux" => {
+                Some(Self::Dnf)
+            }
+            // the minimal variants only have microdnf
+            x if x.starts_with("ubi") && !x.contains("minimal") => Some(Self::Dnf),
+            _ => None,
+        }
+    }
+
+    /// Install `packages` (with optional versions) without leaving the
+    /// package index or caches behind in the layer
+    pub fn install_command<'a>(
+        &self,
+        packages: impl IntoIterator<Item = (&'a str, Option<&'a str>)>,
+    ) -> String {
+        let packages = packages
+            .into_iter()
+            .map(|(name, version)| match (self, version) {
+                (_, None) => name.to_string(),
+                (Self::Apk | Self::Apt, Some(v)) => format!("{}={}", name, v),
+                (Self::Dnf, Some(v)) => format!("{}-{}", name, v),
+            })
+            .join(" ");
+        match self {
+            Self::Apk => format!("apk add --no-cache {}", packages),
+            Self::Apt => format!(
+                "apt-get update \
+                && DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends {} \
+                && rm -rf /var/lib/apt/lists/*",
+                packages
+            ),
+            Self::Dnf => format!(
+                "dnf install -y --setopt=install_weak_deps=False {} && dnf clean all",
+                packages
+            ),
+        }
+    }
+
+    fn binary(&self) -> &'static str {
+        match self {
+            Self::Apk => "apk",
+            Self::Apt => "apt-get",
+            Self::Dnf => "dnf",
+        }
+    }
+}
+
+/// Installs packages with the package manager of the stage's base image,
+/// falling back to picking one when the image is built if it can't tell
+#[derive(Debug, Clone, Default)]
+pub struct InstallPackages {
+    pub packages: Vec<(String, Option<String>)>,
+    pub manager: Option<PackageManager>,
+}
+
+impl InstallPackages {
+    pub fn new(packages: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self {
+            packages: packages
+                .into_iter()
+                .map(|x| (x.to_string(), None))
+                .collect(),
+            manager: Default::default(),
+        }
+    }
+
+    pub fn with_package(mut self, package: impl ToString) -> Self {
+        self.packages.push((package.to_string(), None));
+        self
+    }
+
+    /// Install exactly `version`, in the package manager's version format
+    pub fn with_pinned(mut self, package: impl ToString, version: impl ToString) -> Self {
+        self.packages
+            .push((package.to_string(), Some(version.to_string())));
+        self
+    }
+
+    /// Skip detecting the package manager from the image
+    pub fn with_manager(mut self, manager: PackageManager) -> Self {
+        self.manager = Some(manager);
+        self
+    }
+
+    fn command(&self, manager: Option<PackageManager>) -> String {
+        let packages = || {
+            self.packages
+                .iter()
+                .map(|(name, version)| (name.as_str(), version.as_deref()))
+        };
+        if let Some(manager) = manager {
+            return manager.install_command(packages());
+        }
+        let branches = [
+            PackageManager::Apk,
+            PackageManager::Apt,
+            PackageManager::Dnf,
+        ]
+        .iter()
+        .map(|x| {
+            format!(
+                "command -v {} >/dev/null; then {}",
+                x.binary(),
+                x.install_command(packages())
+            )
+        })
+        .join("; elif ");
+        format!(
+            "if {}; else echo 'no supported package manager' >&2; exit 1; fi",
+            branches
+        )
+    }
+}
+
+impl Fragment for InstallPackages {
+    fn apply(self, docker_file: &mut DockerFile) {
+        if self.packages.is_empty() {
+            return;
+        }
+        let manager = self
+            .manager
+            .or_else(|| PackageManager::detect(docker_file.base_image()));
+        Run::new(self.command(manager)).apply(docker_file);
+    }
+}
+
+impl DockerFile {
+    /// Image the current stage is ultimately built on, following `FROM`s
+    /// of earlier stages
+    fn base_image(&self) -> &ImageRef {
+        let mut image = self.stages.last().unwrap().from.image_ref();
+        for stage in self.stages.iter().rev().skip(1) {
+            let is_stage = image.registry().is_none()
+                && image.tag().is_none()
+                && stage.from.alias() == Some(image.repository());
+            if is_stage {
+                image = stage.from.image_ref();
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use crate::*;
+
+    fn install(from: From, install: InstallPackages) -> String {
+        DockerFile::new(from).then(install).stages()[0]
+            .instructions()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_detect_package_manager() {
+        let detect = |x: &str| PackageManager::detect(&ImageRef::parse(x).unwrap());
+        assert_eq!(detect("alpine:3.19"), Some(PackageManager::Apk));
+        assert_eq!(detect("python:3.12-alpine"), Some(PackageManager::Apk));
+        assert_eq!(detect("rust:1.80"), Some(PackageManager::Apt));
+        assert_eq!(
+            detect("docker.io/library/ubuntu"),
+            Some(PackageManager::Apt)
+        );
+        assert_eq!(detect("fedora:40"), Some(PackageManager::Dnf));
+        assert_eq!(
+            detect("registry.access.redhat.com/ubi9/ubi"),
+            Some(PackageManager::Dnf)
+        );
+        assert_eq!(detect("registry.access.redhat.com/ubi9/ubi-minimal"), None);
+        assert_eq!(detect("nginx"), None);
+    }
+
+    #[test]
+    fn test_install_packages() {
+        let packages = InstallPackages::new(["curl"]).with_pinned("git", "2.43.0-r0");
+        assert_eq!(
+            install(From::image("alpine").with_tag("3.19"), packages),
+            "RUN apk add --no-cache curl git=2.43.0-r0"
+        );
+
+        let packages = InstallPackages::new(["curl", "ca-certificates"]);
+        assert_eq!(
+            install(From::image("debian").with_tag("12"), packages.clone()),
+            "RUN apt-get update \\
+    && DEBIAN_FRONTEND=noninteractive apt-get install -y --no-install-recommends curl ca-certificates \\
+    && rm -rf /var/lib/apt/lists/*"
+        );
+        let df = DockerFile::new(From::image("debian").with_tag("12")).then(packages);
+        assert!(df.validate().unwrap().is_empty());
+
+        let packages = InstallPackages::new(["curl"]).with_pinned("git", "2.43.0");
+        assert_eq!(
+            install(From::image("fedora").with_tag("40"), packages),
+            "RUN dnf install -y --setopt=install_weak_deps=False curl git-2.43.0 \\
+    && dnf clean all"
+        );
+    }
+
+    #[test]
+    fn test_install_packages_in_stage() {
+        let df = DockerFile::new(From::image("alpine").with_tag("3.19").with_alias("base"))
+            .stage(From::image("base"))
+            .then(InstallPackages::new(["curl"]))
+            .stage(From::image("mycorp/base"))
+            .then(InstallPackages::new(["curl"]));
+        assert_eq!(
+            df.stages()[1].instructions().join(""),
+            "RUN apk add --no-cache curl"
+        );
+        let fallback = df.stages()[2].instructions().join("");
+        assert!(
+            fallback.starts_with(
+                "RUN if command -v apk >/dev/null; then apk add --no-cache curl; elif"
+            ),
+            "{}",
+            fallback
+        );
+    }
+}
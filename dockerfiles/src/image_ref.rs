@@ -0,0 +1,273 @@
+use std::{fmt::Display, str::FromStr};
+
+/// A validated `[registry[:port]/]namespace/repo[:tag][@algorithm:digest]`
+///
+/// Parts containing `$` are left unchecked so `ARG`s can be
+/// substituted into them, as in `FROM alpine:${VERSION}`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageRef {
+    registry: Option<String>,
+    repository: String,
+    tag: Option<String>,
+    digest: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidImageRef {
+    pub reference: String,
+    pub reason: String,
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Result<Self, InvalidImageRef> {
+        let invalid = |reason: &str| InvalidImageRef {
+            reference: reference.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (name, digest) = match reference.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (reference, None),
+        };
+        // a `:` after the last `/` starts the tag, before it is a registry port
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, Some(tag)),
+            _ => (name, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest)) if host.contains(['.', ':']) || host == "localhost" => {
+                (Some(host), rest)
+            }
+            _ => (None, name),
+        };
+
+        if let Some(x) = registry.filter(|x| !is_registry(x)) {
+            return Err(invalid(&format!("invalid registry {}", x)));
+        }
+        if repository.is_empty() {
+            return Err(invalid("missing repository"));
+        }
+        if let Some(x) = repository.split('/').find(|x| !is_path_component(x)) {
+            return Err(invalid(&format!("invalid repository component {:?}", x)));
+        }
+        if let Some(x) = tag.filter(|x| !is_tag(x)) {
+            return Err(invalid(&format!("invalid tag {:?}", x)));
+        }
+        if let Some(x) = digest.filter(|x| !is_digest(x)) {
+            return Err(invalid(&format!("invalid digest {:?}", x)));
+        }
+
+        Ok(Self {
+            registry: registry.map(String::from),
+            repository: repository.to_string(),
+            tag: tag.map(String::from),
+            digest: digest.map(String::from),
+        })
+    }
+
+    /// Registry host (with port), `None` for Docker Hub
+    pub fn registry(&self) -> Option<&str> {
+        self.registry.as_deref()
+    }
+
+    pub fn repository(&self) -> &str {
+        &self.repository
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+
+    /// Everything but the tag and digest, e.g. `localhost:5000/app`
+    pub fn name(&self) -> String {
+        match &self.registry {
+            Some(registry) => format!("{}/{}", registry, self.repository),
+            None => self.repository.clone(),
+        }
+    }
+
+    /// # Panics
+    /// If `tag` is not a valid tag
+    #[track_caller]
+    pub fn with_tag(mut self, tag: impl ToString) -> Self {
+        let tag = tag.to_string();
+        assert!(is_tag(&tag), "invalid tag {:?}", tag);
+        self.tag = Some(tag);
+        self
+    }
+
+    /// # Panics
+    /// If `digest` is not a valid `algorithm:hex` digest
+    #[track_caller]
+    pub fn with_digest(mut self, digest: impl ToString) -> Self {
+        let digest = digest.to_string();
+        assert!(is_digest(&digest), "invalid digest {:?}", digest);
+        self.digest = Some(digest);
+        self
+    }
+
+    /// # Panics
+    /// If `registry` is not a valid `host[:port]`
+    #[track_caller]
+    pub fn with_registry(mut self, registry: impl ToString) -> Self {
+        let registry = registry.to_string();
+        assert!(is_registry(&registry), "invalid registry {:?}", registry);
+        self.registry = Some(registry);
+        self
+    }
+}
+
+fn is_registry(host: &str) -> bool {
+    let (host, port) = match host.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (host, None),
+    };
+    host.contains('$')
+        || !host.is_empty()
+            && host
+                .split('.')
+                .all(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+            && port.is_none_or(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// `[a-z0-9]+` joined by `.`, `_`, `__` or any number of `-`
+fn is_path_component(component: &str) -> bool {
+    let alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    component.contains('$')
+        || component.starts_with(alnum)
+            && component.ends_with(alnum)
+            && component
+                .split(alnum)
+                .all(|sep| matches!(sep, "" | "." | "_" | "__") || sep.chars().all(|c| c == '-'))
+}
+
+fn is_tag(tag: &str) -> bool {
+    tag.contains('$')
+        || tag.len() <= 128
+            && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn is_digest(digest: &str) -> bool {
+    let Some((algorithm, hex)) = digest.split_once(':') else {
+        return false;
+    };
+    match algorithm {
+        "sha256" => hex.len() == 64 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')),
+        "sha512" => hex.len() == 128 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')),
+        _ => {
+            !algorithm.is_empty()
+                && !hex.is_empty()
+                && algorithm
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+._-".contains(c))
+                && hex
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "=_-".contains(c))
+        }
+    }
+}
+
+impl FromStr for ImageRef {
+    type Err = InvalidImageRef;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<&str> for ImageRef {
+    type Error = InvalidImageRef;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::parse(value)
+    }
+}
+
+impl Display for ImageRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(x) = &self.tag {
+            write!(f, ":{}", x)?;
+        }
+        if let Some(x) = &self.digest {
+            write!(f, "@{}", x)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for InvalidImageRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid image reference {:?}: {}",
+            self.reference, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidImageRef {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_parse_image_ref() {
+        let r = ImageRef::parse("alpine").unwrap();
+        assert_eq!(
+            (r.registry(), r.repository(), r.tag()),
+            (None, "alpine", None)
+        );
+
+        let r = ImageRef::parse("library/alpine:3.19").unwrap();
+        assert_eq!((r.repository(), r.tag()), ("library/alpine", Some("3.19")));
+
+        let full = format!("localhost:5000/team/app:v1.2-rc_3@{}", DIGEST);
+        let r = ImageRef::parse(&full).unwrap();
+        assert_eq!(r.registry(), Some("localhost:5000"));
+        assert_eq!(r.repository(), "team/app");
+        assert_eq!(r.tag(), Some("v1.2-rc_3"));
+        assert_eq!(r.digest(), Some(DIGEST));
+        assert_eq!(r.to_string(), full);
+
+        let r = ImageRef::parse("ghcr.io/owner/my__repo--x.y").unwrap();
+        assert_eq!(r.registry(), Some("ghcr.io"));
+        assert_eq!(r.name(), "ghcr.io/owner/my__repo--x.y");
+
+        let r = ImageRef::parse("alpine:${VERSION}").unwrap();
+        assert_eq!(r.tag(), Some("${VERSION}"));
+    }
+
+    #[test]
+    fn test_invalid_image_ref() {
+        for x in [
+            "",
+            "Alpine",
+            "alpine:",
+            "alpine:-bad",
+            "alpine@sha256:abc",
+            "my..repo",
+            "repo_",
+            "bad host:80/app",
+            "localhost:port/app",
+        ] {
+            assert!(ImageRef::parse(x).is_err(), "{:?} should be invalid", x);
+        }
+    }
+
+    #[test]
+    fn test_with_tag() {
+        let r = ImageRef::parse("alpine").unwrap().with_tag("3.19");
+        assert_eq!(r.to_string(), "alpine:3.19");
+    }
+}
@@ -1,144 +1,1531 @@
-use std::{borrow::Cow, fmt::Display};
+use std::{
+    any::Any, borrow::Cow, fmt::Display, ops::RangeInclusive, panic::Location, time::Duration,
+};
 
 use docker_derive::Instruction;
 use itertools::Itertools;
 
+use crate::{Fragment, ImageRef};
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct From {
-    image: String,
-    tag: Option<String>,
+    image: ImageRef,
+    platform: Option<String>,
+    alias: Option<String>,
+}
+
+impl From {
+    pub fn new(image: ImageRef) -> Self {
+        Self {
+            image,
+            platform: Default::default(),
+            alias: Default::default(),
+        }
+    }
+
+    /// # Panics
+    /// If `image` is not a valid [ImageRef]
+    #[track_caller]
+    pub fn image(image: impl ToString) -> Self {
+        match ImageRef::parse(&image.to_string()) {
+            Ok(image) => Self::new(image),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// # Panics
+    /// If `tag` is not a valid tag
+    #[track_caller]
+    pub fn with_tag(mut self, tag: impl ToString) -> Self {
+        self.image = self.image.with_tag(tag);
+        self
+    }
+
+    /// `FROM --platform=...`, e.g. `linux/arm64`
+    pub fn with_platform(mut self, platform: impl ToString) -> Self {
+        self.platform = Some(platform.to_string());
+        self
+    }
+
+    pub fn image_ref(&self) -> &ImageRef {
+        &self.image
+    }
+
+    pub fn platform(&self) -> Option<&str> {
+        self.platform.as_deref()
+    }
+
+    /// Name this stage (`FROM image AS alias`) so later stages
+    /// can [Copy::from_stage] it and builds can target it
+    pub fn with_alias(mut self, alias: impl ToString) -> Self {
+        self.alias = Some(alias.to_string());
+        self
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+}
+
+#[derive(Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "crate::serialize::RawCopy"))]
+pub struct Copy {
+    pub sources: Vec<String>,
+    pub to: String,
+    /// Stage (or image) to copy from instead of the build context
+    pub stage: Option<String>,
+    pub chown: Option<String>,
+    pub chmod: Option<String>,
+    pub link: bool,
+    /// Keep the sources' parent directories, this still needs the
+    /// `docker/dockerfile:1.7-labs` syntax
+    pub parents: bool,
+    pub heredocs: Vec<Heredoc>,
+}
+
+impl Copy {
+    pub fn new(from: impl ToString, to: impl ToString) -> Self {
+        Self::sources([from], to)
+    }
+
+    /// # Panics
+    /// If there are several `sources` and `to` doesn't end in `/`
+    #[track_caller]
+    pub fn sources(sources: impl IntoIterator<Item = impl ToString>, to: impl ToString) -> Self {
+        let (sources, to) = sources_to(sources, to);
+        Self {
+            sources,
+            to,
+            stage: Default::default(),
+            chown: Default::default(),
+            chmod: Default::default(),
+            link: false,
+            parents: false,
+            heredocs: Default::default(),
+        }
+    }
+
+    /// # Panics
+    /// If the destination doesn't end in `/`
+    #[track_caller]
+    pub fn with_source(mut self, source: impl ToString) -> Self {
+        assert_dir(&self.to);
+        self.sources.push(source.to_string());
+        self
+    }
+
+    pub fn from_stage(mut self, stage: impl ToString) -> Self {
+        self.stage = Some(stage.to_string());
+        self
+    }
+
+    /// `user[:group]`, by name or id
+    ///
+    /// # Panics
+    /// If `owner` is not of that form
+    #[track_caller]
+    pub fn with_chown(mut self, owner: impl ToString) -> Self {
+        let owner = owner.to_string();
+        assert!(is_chown(&owner), "invalid --chown {:?}", owner);
+        self.chown = Some(owner);
+        self
+    }
+
+    /// Octal permissions, e.g. `0755`
+    ///
+    /// # Panics
+    /// If `mode` is not octal
+    #[track_caller]
+    pub fn with_chmod(mut self, mode: impl ToString) -> Self {
+        let mode = mode.to_string();
+        assert!(is_chmod(&mode), "invalid --chmod {:?}", mode);
+        self.chmod = Some(mode);
+        self
+    }
+
+    /// Copy into a layer of its own, independent of the ones below
+    pub fn with_link(mut self, link: bool) -> Self {
+        self.link = link;
+        self
+    }
+
+    pub fn with_parents(mut self, parents: bool) -> Self {
+        self.parents = parents;
+        self
+    }
+
+    /// Body of a `<<delimiter` that appears in [Self::sources]
+    pub fn with_heredoc(mut self, delimiter: impl ToString, body: impl ToString) -> Self {
+        self.heredocs.push(Heredoc::new(delimiter, body));
+        self
+    }
+
+    /// Whether this needs a BuildKit builder rather than the legacy one
+    pub fn needs_buildkit(&self) -> bool {
+        self.chmod.is_some() || self.link || self.parents || !self.heredocs.is_empty()
+    }
+}
+
+#[track_caller]
+fn sources_to(
+    sources: impl IntoIterator<Item = impl ToString>,
+    to: impl ToString,
+) -> (Vec<String>, String) {
+    let sources: Vec<_> = sources.into_iter().map(|x| x.to_string()).collect();
+    let to = to.to_string();
+    assert!(!sources.is_empty(), "copying without sources to {}", to);
+    if sources.len() > 1 {
+        assert_dir(&to);
+    }
+    (sources, to)
+}
+
+#[track_caller]
+fn assert_dir(to: &str) {
+    assert!(
+        is_dir(to),
+        "destination {:?} of several sources must end in /",
+        to
+    );
+}
+
+fn is_dir(to: &str) -> bool {
+    to.ends_with('/') || to.contains('$')
+}
+
+/// What the constructors of [Copy] and [Add] check, for ones made
+/// otherwise, e.g. deserialized
+#[cfg(feature = "serde")]
+pub(crate) fn check_copy(
+    sources: &[String],
+    to: &str,
+    chown: Option<&str>,
+    chmod: Option<&str>,
+) -> Result<(), String> {
+    if sources.is_empty() {
+        return Err(format!("copying without sources to {}", to));
+    }
+    if sources.len() > 1 && !is_dir(to) {
+        return Err(format!(
+            "destination {:?} of several sources must end in /",
+            to
+        ));
+    }
+    if let Some(owner) = chown.filter(|x| !is_chown(x)) {
+        return Err(format!("invalid --chown {:?}", owner));
+    }
+    if let Some(mode) = chmod.filter(|x| !is_chmod(x)) {
+        return Err(format!("invalid --chmod {:?}", mode));
+    }
+    Ok(())
+}
+
+pub(crate) fn is_chown(owner: &str) -> bool {
+    let valid = |x: &str| !x.is_empty() && !x.contains(|c: char| c.is_whitespace() || c == ':');
+    match owner.split_once(':') {
+        Some((user, group)) => valid(user) && valid(group),
+        None => valid(owner),
+    }
+}
+
+pub(crate) fn is_chmod(mode: &str) -> bool {
+    mode.starts_with('$')
+        || (1..=4).contains(&mode.len()) && mode.chars().all(|c| matches!(c, '0'..='7'))
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Volume {
+    pub paths: Vec<String>,
+}
+
+impl Volume {
+    /// Declares both `from` and `to` as volumes
+    pub fn new(from: impl ToString, to: impl ToString) -> Self {
+        Self::paths([from.to_string(), to.to_string()])
+    }
+
+    pub fn paths(paths: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self {
+            paths: paths.into_iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Run {
+    pub cmd: Form,
+    pub heredocs: Vec<Heredoc>,
+    /// `--mount`s, these need BuildKit
+    pub mounts: Vec<Mount>,
+    /// `--network`, this needs BuildKit
+    pub network: Option<Network>,
+}
+
+impl Run {
+    pub fn new(cmd: impl ToString) -> Self {
+        Self::with_form(Form::shell(cmd))
+    }
+
+    pub fn exec(cmds: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self::with_form(Form::exec(cmds))
+    }
+
+    pub(crate) fn with_form(cmd: Form) -> Self {
+        Self {
+            cmd,
+            heredocs: Default::default(),
+            mounts: Default::default(),
+            network: Default::default(),
+        }
+    }
+
+    /// e.g. `Run::new("cargo build").with_mount(Mount::cache("/usr/local/cargo/registry"))`
+    pub fn with_mount(mut self, mount: Mount) -> Self {
+        self.mounts.push(mount);
+        self
+    }
+
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Whether this needs a BuildKit builder rather than the legacy one
+    pub fn needs_buildkit(&self) -> bool {
+        !self.mounts.is_empty() || self.network.is_some() || !self.heredocs.is_empty()
+    }
+
+    /// Body of a `<<delimiter` that appears in the command,
+    /// e.g. `Run::new("<<EOF").with_heredoc("EOF", "apk add curl\n")`
+    pub fn with_heredoc(mut self, delimiter: impl ToString, body: impl ToString) -> Self {
+        self.heredocs.push(Heredoc::new(delimiter, body));
+        self
+    }
+}
+
+/// `RUN --mount=type=...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "lowercase"))]
+pub enum Mount {
+    /// A directory kept between builds, e.g. cargo's registry or `target`
+    Cache {
+        target: String,
+        /// Mounts with the same id share a cache, defaults to `target`
+        id: Option<String>,
+        sharing: Option<Sharing>,
+    },
+    /// Files from the build context, or from `from` if given, read only
+    /// unless `rw` (and even then writes are discarded)
+    Bind {
+        target: String,
+        source: Option<String>,
+        from: Option<String>,
+        rw: bool,
+    },
+    /// A secret passed to the build, at `/run/secrets/<id>` unless
+    /// `target` is given. Builds through bollard have no secrets to
+    /// pass, so the mount is empty and `required` ones are refused
+    Secret {
+        id: String,
+        target: Option<String>,
+        required: bool,
+    },
+}
+
+impl Mount {
+    pub fn cache(target: impl ToString) -> Self {
+        Self::Cache {
+            target: target.to_string(),
+            id: Default::default(),
+            sharing: Default::default(),
+        }
+    }
+
+    pub fn bind(target: impl ToString) -> Self {
+        Self::Bind {
+            target: target.to_string(),
+            source: Default::default(),
+            from: Default::default(),
+            rw: false,
+        }
+    }
+
+    pub fn secret(id: impl ToString) -> Self {
+        Self::Secret {
+            id: id.to_string(),
+            target: Default::default(),
+            required: false,
+        }
+    }
+}
+
+/// How concurrent builds use the same cache mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Sharing {
+    Shared,
+    Private,
+    Locked,
+}
+
+/// `RUN --network=...`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Network {
+    Default,
+    None,
+    Host,
+}
+
+/// Lines following an instruction up to a line holding only `delimiter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Heredoc {
+    pub delimiter: String,
+    pub body: String,
+}
+
+impl Heredoc {
+    pub fn new(delimiter: impl ToString, body: impl ToString) -> Self {
+        let mut body = body.to_string();
+        if !body.is_empty() && !body.ends_with('\n') {
+            body.push('\n');
+        }
+        Self {
+            delimiter: delimiter.to_string(),
+            body,
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[instruction(keyword = "ENTRYPOINT")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntryPoint {
+    pub cmd: Form,
+}
+
+impl EntryPoint {
+    pub fn shell(cmd: impl ToString) -> Self {
+        Self {
+            cmd: Form::shell(cmd),
+        }
+    }
+
+    pub fn exec(cmds: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self {
+            cmd: Form::exec(cmds),
+        }
+    }
+}
+
+/// The two ways Docker accepts a command: a string run through the shell
+/// or a JSON array exec'd directly
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Form {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl Form {
+    pub fn shell(cmd: impl ToString) -> Self {
+        Self::Shell(cmd.to_string())
+    }
+
+    pub fn exec(cmds: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self::Exec(cmds.into_iter().map(|x| x.to_string()).collect())
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Env {
+    pub key: String,
+    pub value: String,
+}
+
+impl Env {
+    pub fn new(key: impl ToString, value: impl ToString) -> Self {
+        Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Arg {
+    pub name: String,
+    pub default: Option<String>,
+}
+
+impl Arg {
+    pub fn new(name: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            default: Default::default(),
+        }
+    }
+
+    pub fn with_default(mut self, default: impl ToString) -> Self {
+        self.default = Some(default.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[instruction(keyword = "WORKDIR")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WorkDir {
+    pub path: String,
+}
+
+impl WorkDir {
+    pub fn new(path: impl ToString) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct User {
+    pub user: String,
+    pub group: Option<String>,
+}
+
+impl User {
+    pub fn new(user: impl ToString) -> Self {
+        Self {
+            user: user.to_string(),
+            group: Default::default(),
+        }
+    }
+
+    pub fn with_group(mut self, group: impl ToString) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// What an [Expose] exposes
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Ports {
+    Port(u16),
+    /// `8000-8010`, both included
+    Range(u16, u16),
+    /// Expanded by the builder, as written, e.g. `${PORT}`
+    Variable(String),
+}
+
+impl std::convert::From<u16> for Ports {
+    fn from(port: u16) -> Self {
+        Ports::Port(port)
+    }
+}
+
+impl std::convert::From<RangeInclusive<u16>> for Ports {
+    fn from(ports: RangeInclusive<u16>) -> Self {
+        Ports::Range(*ports.start(), *ports.end())
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Expose {
+    pub port: Ports,
+    pub protocol: Protocol,
+}
+
+impl Expose {
+    pub fn tcp(port: impl Into<Ports>) -> Self {
+        Self {
+            port: port.into(),
+            protocol: Protocol::Tcp,
+        }
+    }
+
+    pub fn udp(port: impl Into<Ports>) -> Self {
+        Self {
+            port: port.into(),
+            protocol: Protocol::Udp,
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Label {
+    pub key: String,
+    pub value: String,
+}
+
+impl Label {
+    pub fn new(key: impl ToString, value: impl ToString) -> Self {
+        Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[instruction(keyword = "CMD")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Cmd {
+    pub cmd: Form,
+}
+
+impl Cmd {
+    pub fn shell(cmd: impl ToString) -> Self {
+        Self {
+            cmd: Form::shell(cmd),
+        }
+    }
+
+    pub fn exec(cmds: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self {
+            cmd: Form::exec(cmds),
+        }
+    }
+}
+
+/// Like [Copy] but sources may also be URLs or git repositories, and
+/// local tar archives are extracted
+#[derive(Debug, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "crate::serialize::RawAdd"))]
+pub struct Add {
+    pub sources: Vec<String>,
+    pub to: String,
+    pub chown: Option<String>,
+    pub chmod: Option<String>,
+    pub link: bool,
+    pub heredocs: Vec<Heredoc>,
+}
+
+impl Add {
+    pub fn new(from: impl ToString, to: impl ToString) -> Self {
+        Self::sources([from], to)
+    }
+
+    /// # Panics
+    /// If there are several `sources` and `to` doesn't end in `/`
+    #[track_caller]
+    pub fn sources(sources: impl IntoIterator<Item = impl ToString>, to: impl ToString) -> Self {
+        let (sources, to) = sources_to(sources, to);
+        Self {
+            sources,
+            to,
+            chown: Default::default(),
+            chmod: Default::default(),
+            link: false,
+            heredocs: Default::default(),
+        }
+    }
+
+    /// # Panics
+    /// If the destination doesn't end in `/`
+    #[track_caller]
+    pub fn with_source(mut self, source: impl ToString) -> Self {
+        assert_dir(&self.to);
+        self.sources.push(source.to_string());
+        self
+    }
+
+    /// # Panics
+    /// If `owner` is not `user[:group]`
+    #[track_caller]
+    pub fn with_chown(mut self, owner: impl ToString) -> Self {
+        let owner = owner.to_string();
+        assert!(is_chown(&owner), "invalid --chown {:?}", owner);
+        self.chown = Some(owner);
+        self
+    }
+
+    /// # Panics
+    /// If `mode` is not octal
+    #[track_caller]
+    pub fn with_chmod(mut self, mode: impl ToString) -> Self {
+        let mode = mode.to_string();
+        assert!(is_chmod(&mode), "invalid --chmod {:?}", mode);
+        self.chmod = Some(mode);
+        self
+    }
+
+    pub fn with_link(mut self, link: bool) -> Self {
+        self.link = link;
+        self
+    }
+
+    /// Body of a `<<delimiter` that appears in [Self::sources]
+    pub fn with_heredoc(mut self, delimiter: impl ToString, body: impl ToString) -> Self {
+        self.heredocs.push(Heredoc::new(delimiter, body));
+        self
+    }
+
+    pub fn needs_buildkit(&self) -> bool {
+        self.chmod.is_some() || self.link || !self.heredocs.is_empty()
+    }
 }
 
-impl From {
-    pub fn image(image: impl ToString) -> Self {
-        Self {
-            image: image.to_string(),
-            tag: Default::default(),
+#[derive(Debug, Instruction)]
+#[instruction(keyword = "SHELL")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Shell {
+    #[instruction(json_array)]
+    pub cmds: Vec<String>,
+}
+
+impl Shell {
+    pub fn new(cmds: impl IntoIterator<Item = impl ToString>) -> Self {
+        Self {
+            cmds: cmds.into_iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Instruction)]
+#[instruction(keyword = "STOPSIGNAL")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StopSignal {
+    pub signal: String,
+}
+
+impl StopSignal {
+    pub fn new(signal: impl ToString) -> Self {
+        Self {
+            signal: signal.to_string(),
+        }
+    }
+}
+
+/// `HEALTHCHECK NONE` when there is no `cmd`, which disables any
+/// healthcheck inherited from the base image
+#[derive(Debug, Default, Instruction)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthCheck {
+    pub cmd: Option<Form>,
+    pub interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub start_period: Option<Duration>,
+    pub start_interval: Option<Duration>,
+    pub retries: Option<u32>,
+}
+
+impl HealthCheck {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn cmd(cmd: Form) -> Self {
+        Self {
+            cmd: Some(cmd),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_start_period(mut self, start_period: Duration) -> Self {
+        self.start_period = Some(start_period);
+        self
+    }
+
+    pub fn with_start_interval(mut self, start_interval: Duration) -> Self {
+        self.start_interval = Some(start_interval);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+}
+
+#[derive(Instruction)]
+#[instruction(keyword = "ONBUILD")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OnBuild {
+    pub instr: Box<dyn Instruction>,
+}
+
+impl OnBuild {
+    pub fn new(instr: impl Instruction + 'static) -> Self {
+        Self {
+            instr: Box::new(instr),
+        }
+    }
+}
+
+/// A `FROM` and everything up to the next `FROM`
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stage {
+    pub(crate) from: From,
+    #[cfg_attr(feature = "serde", serde(rename = "instructions"))]
+    pub(crate) instrs: Vec<Box<dyn Instruction>>,
+}
+
+impl Stage {
+    pub(crate) fn new(from: From) -> Self {
+        Self {
+            from,
+            instrs: Default::default(),
+        }
+    }
+
+    pub fn from(&self) -> &From {
+        &self.from
+    }
+
+    pub fn from_mut(&mut self) -> &mut From {
+        &mut self.from
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &dyn Instruction> {
+        self.instrs.iter().map(|x| x.as_ref())
+    }
+
+    pub fn instructions_mut(&mut self) -> impl Iterator<Item = &mut dyn Instruction> {
+        self.instrs.iter_mut().map(|x| x.as_mut())
+    }
+
+    /// Insert `instr` before the first instruction matching `pred`,
+    /// returning whether one did
+    pub fn insert_before(
+        &mut self,
+        mut pred: impl FnMut(&dyn Instruction) -> bool,
+        instr: impl Instruction + 'static,
+    ) -> bool {
+        match self.instrs.iter().position(|x| pred(x.as_ref())) {
+            Some(i) => {
+                self.instrs.insert(i, Box::new(instr));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn retain(&mut self, mut pred: impl FnMut(&dyn Instruction) -> bool) {
+        self.instrs.retain(|x| pred(x.as_ref()));
+    }
+
+    /// Replace every instruction matching `pred` with what `with` makes of
+    /// it, returning how many were replaced
+    pub fn replace<I: Instruction>(
+        &mut self,
+        mut pred: impl FnMut(&dyn Instruction) -> bool,
+        mut with: impl FnMut(&dyn Instruction) -> I,
+    ) -> usize {
+        let mut replaced = 0;
+        for x in self.instrs.iter_mut().filter(|x| pred(x.as_ref())) {
+            *x = Box::new(with(x.as_ref()));
+            replaced += 1;
+        }
+        replaced
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "crate::serialize::RawDockerFile"))]
+pub struct DockerFile {
+    /// Parser directives such as `# syntax=docker/dockerfile:1`
+    pub(crate) directives: Vec<(String, String)>,
+    /// `ARG`s declared before the first `FROM`, usable in `FROM` lines
+    pub(crate) args: Vec<Arg>,
+    pub(crate) stages: Vec<Stage>,
+    /// Whether the last instruction is the [Self::entry_point], which
+    /// stays last as instructions and stages are added
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) entry_point: bool,
+    /// Where [Self::then] was called for each instruction, by the address
+    /// of its box, which stays put as instructions are added and removed,
+    /// and with its text to tell whether it was changed since
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) locations: Vec<(usize, String, &'static Location<'static>)>,
+}
+
+impl DockerFile {
+    pub fn new(from: From) -> Self {
+        Self {
+            directives: Default::default(),
+            args: Default::default(),
+            stages: vec![Stage::new(from)],
+            entry_point: false,
+            locations: Default::default(),
+        }
+    }
+
+    pub fn with_directive(mut self, name: impl ToString, value: impl ToString) -> Self {
+        self.directives.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn directive(&self, name: &str) -> Option<&str> {
+        self.directives
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Declare an `ARG` before the first `FROM`
+    pub fn global_arg(mut self, arg: Arg) -> Self {
+        self.args.push(arg);
+        self
+    }
+
+    /// Start a new build stage, instructions added by [Self::then]
+    /// afterwards go into this stage
+    pub fn stage(mut self, from: From) -> Self {
+        let entry_point = self.take_entry_point();
+        self.stages.push(Stage::new(from));
+        self.put_entry_point(entry_point);
+        self
+    }
+
+    /// The exec form `ENTRYPOINT` of the image, rendered last whatever is
+    /// added afterwards. Calling it again replaces it, unlike adding an
+    /// [EntryPoint] with [Self::then]
+    #[track_caller]
+    pub fn entry_point(mut self, entry_point: impl IntoIterator<Item = impl ToString>) -> Self {
+        if let Some(old) = self.take_entry_point() {
+            self.locations.retain(|(x, ..)| *x != address(&*old));
         }
+        let mut df = self.then(EntryPoint::exec(entry_point));
+        df.entry_point = true;
+        df
     }
 
-    pub fn with_tag(mut self, tag: impl ToString) -> Self {
-        self.tag = Some(tag.to_string());
+    /// Remove the [Self::entry_point] from the end, unless it was removed
+    /// since, e.g. by [Self::retain]
+    fn take_entry_point(&mut self) -> Option<Box<dyn Instruction>> {
+        if !std::mem::take(&mut self.entry_point) {
+            return None;
+        }
+        let instrs = &mut self.current_stage().instrs;
+        match instrs.last() {
+            Some(x) if x.is::<EntryPoint>() => instrs.pop(),
+            _ => None,
+        }
+    }
+
+    fn put_entry_point(&mut self, entry_point: Option<Box<dyn Instruction>>) {
+        if let Some(x) = entry_point {
+            self.current_stage().instrs.push(x);
+            self.entry_point = true;
+        }
+    }
+
+    /// Add an instruction, or several with a [Fragment], to the current
+    /// stage, remembering the caller for [Self::location_of]
+    #[track_caller]
+    pub fn then(mut self, fragment: impl Fragment) -> Self {
+        let location = Location::caller();
+        let entry_point = self.take_entry_point();
+        let start = self.current_stage().instrs.len();
+        fragment.apply(&mut self);
+        let added: Vec<_> = self
+            .current_stage()
+            .instrs
+            .get(start..)
+            .into_iter()
+            .flatten()
+            .map(|x| (address(&**x), x.to_string(), location))
+            .collect();
+        self.locations.extend(added);
+        self.put_entry_point(entry_point);
         self
     }
+
+    /// Each instruction added with [Self::then] as `((stage, index), text,
+    /// caller)` in the order they are rendered, `index` being that in
+    /// [Stage::instructions]. Instructions changed since, e.g. by
+    /// [Self::optimize], have none
+    pub fn locations(
+        &self,
+    ) -> impl Iterator<Item = ((usize, usize), &str, &'static Location<'static>)> {
+        self.stages.iter().enumerate().flat_map(move |(i, stage)| {
+            stage
+                .instrs
+                .iter()
+                .enumerate()
+                .filter_map(move |(j, instr)| {
+                    let text = instr.to_string();
+                    // newest first, a box may reuse the address of a removed one
+                    let (_, text, location) = self
+                        .locations
+                        .iter()
+                        .rev()
+                        .find(|(x, y, _)| *x == address(&**instr) && *y == text)?;
+                    Some(((i, j), text.as_str(), *location))
+                })
+        })
+    }
+
+    /// Where the first instruction rendered as `instruction` was added with
+    /// [Self::then], ignoring line continuations and extra whitespace, see
+    /// [Self::locations]
+    pub fn location_of(&self, instruction: &str) -> Option<&'static Location<'static>> {
+        let instruction = normalize(instruction);
+        self.locations()
+            .find(|(_, x, _)| normalize(x) == instruction)
+            .map(|(.., location)| location)
+    }
+
+    /// Whether any instruction uses options or heredocs only BuildKit
+    /// understands, see [Run::needs_buildkit] and [Copy::needs_buildkit]
+    pub fn needs_buildkit(&self) -> bool {
+        self.instructions_of::<Run>().any(Run::needs_buildkit)
+            || self.instructions_of::<Copy>().any(Copy::needs_buildkit)
+            || self.instructions_of::<Add>().any(Add::needs_buildkit)
+    }
+
+    /// The `ARG`s before the first `FROM`, see [Self::global_arg]
+    pub fn global_args(&self) -> &[Arg] {
+        &self.args
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    pub fn stages_mut(&mut self) -> &mut [Stage] {
+        &mut self.stages
+    }
+
+    /// The stage named `alias` by [From::with_alias]
+    pub fn stage_named(&self, alias: &str) -> Option<&Stage> {
+        self.stages.iter().find(|x| x.from.alias() == Some(alias))
+    }
+
+    pub fn stage_named_mut(&mut self, alias: &str) -> Option<&mut Stage> {
+        self.stages
+            .iter_mut()
+            .find(|x| x.from.alias() == Some(alias))
+    }
+
+    /// Instructions of all stages in order, without the `FROM`s
+    pub fn instructions(&self) -> impl Iterator<Item = &dyn Instruction> {
+        self.stages.iter().flat_map(Stage::instructions)
+    }
+
+    pub fn instructions_mut(&mut self) -> impl Iterator<Item = &mut dyn Instruction> {
+        self.stages.iter_mut().flat_map(Stage::instructions_mut)
+    }
+
+    /// e.g. `df.instructions_of::<Run>()` for all the `RUN`s
+    pub fn instructions_of<T: Instruction>(&self) -> impl Iterator<Item = &T> {
+        self.instructions().filter_map(|x| x.downcast_ref())
+    }
+
+    pub fn instructions_of_mut<T: Instruction>(&mut self) -> impl Iterator<Item = &mut T> {
+        self.instructions_mut().filter_map(|x| x.downcast_mut())
+    }
+
+    /// Insert `instr` before the first instruction of any stage matching
+    /// `pred`, returning whether one did
+    pub fn insert_before(
+        &mut self,
+        mut pred: impl FnMut(&dyn Instruction) -> bool,
+        instr: impl Instruction + 'static,
+    ) -> bool {
+        match self
+            .stages
+            .iter_mut()
+            .find(|x| x.instructions().any(&mut pred))
+        {
+            Some(stage) => stage.insert_before(pred, instr),
+            None => false,
+        }
+    }
+
+    /// Keep only the instructions of all stages matching `pred`
+    pub fn retain(&mut self, mut pred: impl FnMut(&dyn Instruction) -> bool) {
+        self.stages.iter_mut().for_each(|x| x.retain(&mut pred));
+    }
+
+    /// [Stage::replace] in all stages
+    pub fn replace<I: Instruction>(
+        &mut self,
+        mut pred: impl FnMut(&dyn Instruction) -> bool,
+        mut with: impl FnMut(&dyn Instruction) -> I,
+    ) -> usize {
+        self.stages
+            .iter_mut()
+            .map(|x| x.replace(&mut pred, &mut with))
+            .sum()
+    }
+
+    pub(crate) fn current_stage(&mut self) -> &mut Stage {
+        self.stages.last_mut().expect("docker file without stage")
+    }
 }
 
-#[derive(Instruction)]
-pub struct Copy {
-    pub from: String,
-    pub to: String,
+fn address(instruction: &dyn Instruction) -> usize {
+    instruction as *const dyn Instruction as *const () as usize
 }
 
-impl Copy {
-    pub fn new(from: impl ToString, to: impl ToString) -> Self {
-        Self {
-            from: from.to_string(),
-            to: to.to_string(),
-        }
+fn normalize(instruction: &str) -> String {
+    instruction
+        .replace("\\\n", " ")
+        .split_whitespace()
+        .join(" ")
+}
+
+/// Implement with `#[derive(Instruction)]`
+pub trait Instruction: Display + Any {
+    /// e.g. `COPY`
+    fn keyword(&self) -> &'static str;
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl dyn Instruction {
+    pub fn is<T: Instruction>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+
+    pub fn downcast_ref<T: Instruction>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+
+    pub fn downcast_mut<T: Instruction>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
     }
 }
 
-#[derive(Debug, Instruction)]
-pub struct Volume {
-    pub from: String,
-    pub to: String,
+/******************* DISPLAYS *******************/
+impl Display for From {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FROM ")?;
+        if let Some(x) = &self.platform {
+            write!(f, "--platform={} ", x)?;
+        }
+        write!(f, "{}", self.image)?;
+        if let Some(x) = &self.alias {
+            write!(f, " AS {}", x)?;
+        }
+        Ok(())
+    }
 }
 
-impl Volume {
-    pub fn new(from: impl ToString, to: impl ToString) -> Self {
-        Self {
-            from: from.to_string(),
-            to: to.to_string(),
+impl Display for Copy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "COPY ")?;
+        if let Some(x) = &self.stage {
+            write!(f, "--from={} ", x)?;
+        }
+        fmt_copy_flags(f, &self.chown, &self.chmod, self.link)?;
+        if self.parents {
+            write!(f, "--parents ")?;
         }
+        fmt_paths(f, self.sources.iter().chain([&self.to]))?;
+        fmt_heredocs(f, &self.heredocs)
     }
 }
 
-#[derive(Debug, Instruction)]
-pub struct Run {
-    pub cmd: String,
+fn fmt_copy_flags(
+    f: &mut std::fmt::Formatter<'_>,
+    chown: &Option<String>,
+    chmod: &Option<String>,
+    link: bool,
+) -> std::fmt::Result {
+    if let Some(x) = chown {
+        write!(f, "--chown={} ", x)?;
+    }
+    if let Some(x) = chmod {
+        write!(f, "--chmod={} ", x)?;
+    }
+    if link {
+        write!(f, "--link ")?;
+    }
+    Ok(())
+}
+
+impl Display for Volume {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VOLUME ")?;
+        fmt_paths(f, &self.paths)
+    }
+}
+
+/// Shell-form `RUN`s longer than this are broken up at their `&&`s
+const MAX_RUN_WIDTH: usize = 80;
+
+impl Display for Run {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_escaped(f, '\\')
+    }
 }
 
 impl Run {
-    pub fn new(cmd: impl ToString) -> Self {
-        Self {
-            cmd: cmd.to_string(),
+    /// Rendered in a Dockerfile whose `escape` directive is `escape`,
+    /// which continues the lines of a long RUN
+    pub(crate) fn fmt_escaped(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        escape: char,
+    ) -> std::fmt::Result {
+        let mut run = "RUN ".to_string();
+        for x in &self.mounts {
+            run += &format!("--mount={} ", x);
+        }
+        if let Some(x) = &self.network {
+            run += &format!("--network={} ", x);
+        }
+        match &self.cmd {
+            Form::Shell(cmd)
+                if self.heredocs.is_empty() && run.len() + cmd.len() > MAX_RUN_WIDTH =>
+            {
+                let (first, rest) = split_and(cmd);
+                write!(f, "{}{}", run, first)?;
+                for x in rest {
+                    write!(f, " {}\n    && {}", escape, x)?;
+                }
+                Ok(())
+            }
+            cmd => {
+                write!(f, "{}{}", run, cmd)?;
+                fmt_heredocs(f, &self.heredocs)
+            }
         }
     }
 }
 
-pub struct EntryPoint {
-    pub cmds: Vec<String>,
+impl Display for Mount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mount::Cache {
+                target,
+                id,
+                sharing,
+            } => {
+                write!(f, "type=cache,target={}", target)?;
+                if let Some(x) = id {
+                    write!(f, ",id={}", x)?;
+                }
+                if let Some(x) = sharing {
+                    write!(f, ",sharing={}", x)?;
+                }
+            }
+            Mount::Bind {
+                target,
+                source,
+                from,
+                rw,
+            } => {
+                write!(f, "type=bind,target={}", target)?;
+                if let Some(x) = source {
+                    write!(f, ",source={}", x)?;
+                }
+                if let Some(x) = from {
+                    write!(f, ",from={}", x)?;
+                }
+                if *rw {
+                    write!(f, ",rw=true")?;
+                }
+            }
+            Mount::Secret {
+                id,
+                target,
+                required,
+            } => {
+                write!(f, "type=secret,id={}", id)?;
+                if let Some(x) = target {
+                    write!(f, ",target={}", x)?;
+                }
+                if *required {
+                    write!(f, ",required=true")?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-pub struct DockerFile {
-    from: From,
-    entry_point: Option<EntryPoint>,
-    instrs: Vec<Box<dyn Instruction>>,
+impl Display for Sharing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sharing::Shared => write!(f, "shared"),
+            Sharing::Private => write!(f, "private"),
+            Sharing::Locked => write!(f, "locked"),
+        }
+    }
 }
 
-impl DockerFile {
-    pub fn new(from: From) -> Self {
-        Self {
-            from,
-            entry_point: Default::default(),
-            instrs: Default::default(),
+impl Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Network::Default => write!(f, "default"),
+            Network::None => write!(f, "none"),
+            Network::Host => write!(f, "host"),
         }
     }
+}
 
-    pub fn entry_point(mut self, entry_point: impl IntoIterator<Item = impl ToString>) -> Self {
-        let cmds = entry_point.into_iter().map(|x| x.to_string()).collect();
-        self.entry_point = Some(EntryPoint { cmds });
-        self
+/// Split a shell command at every `&&` that is not quoted
+pub(crate) fn split_and(cmd: &str) -> (&str, Vec<&str>) {
+    let mut parts = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    let mut chars = cmd.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None | Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if q == c => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '&') if chars.next_if(|(_, c)| *c == '&').is_some() => {
+                parts.push(cmd[start..i].trim());
+                start = i + 2;
+            }
+            _ => {}
+        }
     }
+    parts.push(cmd[start..].trim());
+    let first = parts.remove(0);
+    (first, parts)
+}
 
-    pub fn then(mut self, instr: impl Instruction + 'static) -> Self {
-        self.instrs.push(Box::new(instr));
-        self
+/// Paths as separate words, or as a JSON array when one of them
+/// wouldn't survive being split on whitespace
+fn fmt_paths(
+    f: &mut std::fmt::Formatter<'_>,
+    paths: impl IntoIterator<Item = impl AsRef<str>>,
+) -> std::fmt::Result {
+    let paths: Vec<_> = paths.into_iter().collect();
+    let needs_json = paths.iter().map(AsRef::as_ref).any(|x: &str| {
+        x.is_empty() || x.starts_with(['-', '[']) || x.contains(char::is_whitespace)
+    });
+    match needs_json {
+        true => fmt_json_array(f, paths),
+        false => write!(f, "{}", paths.iter().map(AsRef::as_ref).join(" ")),
     }
 }
 
-pub trait Instruction: Display {}
-/******************* DISPLAYS *******************/
-impl Display for From {
+pub(crate) fn fmt_json_array(
+    f: &mut std::fmt::Formatter<'_>,
+    items: impl IntoIterator<Item = impl AsRef<str>>,
+) -> std::fmt::Result {
+    let items = items
+        .into_iter()
+        .map(|x| json_string(x.as_ref()))
+        .join(", ");
+    write!(f, "[{}]", items)
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn fmt_heredocs(f: &mut std::fmt::Formatter<'_>, heredocs: &[Heredoc]) -> std::fmt::Result {
+    for heredoc in heredocs {
+        write!(f, "\n{}{}", heredoc.body, heredoc.delimiter)?;
+    }
+    Ok(())
+}
+
+impl Display for Form {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Form::Shell(cmd) => write!(f, "{}", cmd),
+            Form::Exec(cmds) => fmt_json_array(f, cmds),
+        }
+    }
+}
+
+/// Quote a word for `ENV`/`LABEL`/`ARG` if Docker would otherwise split
+/// or unescape it
+pub(crate) fn quote(word: &str) -> Cow<'_, str> {
+    let plain = !word.is_empty()
+        && !word
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\'));
+    match plain {
+        true => word.into(),
+        false => force_quote(word).into(),
+    }
+}
+
+fn force_quote(word: &str) -> String {
+    format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Display for Env {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ENV {}={}", self.key, quote(&self.value))
+    }
+}
+
+impl Display for Arg {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FROM {}", self.image)?;
-        if let Some(x) = &self.tag {
-            write!(f, "{}", x)?;
+        write!(f, "ARG {}", self.name)?;
+        if let Some(x) = &self.default {
+            write!(f, "={}", quote(x))?;
         }
         Ok(())
     }
 }
 
-impl Display for Copy {
+impl Display for User {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "COPY {} {}", self.from, self.to)
+        write!(f, "USER {}", self.user)?;
+        if let Some(x) = &self.group {
+            write!(f, ":{}", x)?;
+        }
+        Ok(())
     }
 }
 
-impl Display for Volume {
+impl Display for Ports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ports::Port(port) => write!(f, "{}", port),
+            Ports::Range(start, end) => write!(f, "{}-{}", start, end),
+            Ports::Variable(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl Display for Expose {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "VOLUME {} {}", self.from, self.to)
+        match self.protocol {
+            Protocol::Tcp => write!(f, "EXPOSE {}", self.port),
+            Protocol::Udp => write!(f, "EXPOSE {}/udp", self.port),
+        }
     }
 }
 
-impl Display for Run {
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LABEL {}={}", quote(&self.key), force_quote(&self.value))
+    }
+}
+
+impl Display for Add {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RUN {}", self.cmd)
+        write!(f, "ADD ")?;
+        fmt_copy_flags(f, &self.chown, &self.chmod, self.link)?;
+        fmt_paths(f, self.sources.iter().chain([&self.to]))?;
+        fmt_heredocs(f, &self.heredocs)
+    }
+}
+
+/// Docker parses these with Go's `time.ParseDuration`
+fn fmt_duration(d: &Duration) -> String {
+    match d.subsec_millis() {
+        0 => format!("{}s", d.as_secs()),
+        _ => format!("{}ms", d.as_millis()),
     }
 }
 
-impl Display for EntryPoint {
+impl Display for HealthCheck {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let cmds = self.cmds.iter().map(|x| format!("\"{}\"", x)).join(", ");
-        write!(f, "ENTRYPOINT [{}]", cmds)
+        let Some(cmd) = &self.cmd else {
+            return write!(f, "HEALTHCHECK NONE");
+        };
+        write!(f, "HEALTHCHECK")?;
+        let durations = [
+            ("interval", &self.interval),
+            ("timeout", &self.timeout),
+            ("start-period", &self.start_period),
+            ("start-interval", &self.start_interval),
+        ];
+        for (flag, d) in durations {
+            if let Some(d) = d {
+                write!(f, " --{}={}", flag, fmt_duration(d))?;
+            }
+        }
+        if let Some(x) = &self.retries {
+            write!(f, " --retries={}", x)?;
+        }
+        write!(f, " CMD {}", cmd)
     }
 }
 
 impl Display for DockerFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in &self.directives {
+            writeln!(f, "# {}={}", name, value)?;
+        }
+        for arg in &self.args {
+            writeln!(f, "{}", arg)?;
+        }
+        let escape = self
+            .directive("escape")
+            .and_then(|x| x.chars().next())
+            .unwrap_or('\\');
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            stage.fmt_escaped(f, escape)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_escaped(f, '\\')
+    }
+}
+
+impl Stage {
+    fn fmt_escaped(&self, f: &mut std::fmt::Formatter<'_>, escape: char) -> std::fmt::Result {
         writeln!(f, "{}", self.from)?;
-        writeln!(f, "{}", self.instrs.iter().join("\n"))?;
-        if let Some(p) = &self.entry_point {
-            writeln!(f, "{}", p)?;
+        for instr in &self.instrs {
+            match instr.downcast_ref::<Run>() {
+                Some(run) => run.fmt_escaped(f, escape)?,
+                None => write!(f, "{}", instr)?,
+            }
+            writeln!(f)?;
         }
         Ok(())
     }
@@ -154,6 +1541,7 @@ impl std::convert::From<&DockerFile> for Cow<'_, str> {
 mod test {
     use super::*;
     use crate::instruction::Volume;
+    use proptest::{collection::vec, prelude::*};
 
     #[test]
     fn test_docker_file_creation() {
@@ -173,4 +1561,345 @@ ENTRYPOINT ["echo", "hello"]
 
         assert_eq!(df.to_string().trim(), df_exp.trim());
     }
+
+    #[test]
+    fn test_docker_file_instructions() {
+        let df = DockerFile::new(From::image("alpine"))
+            .then(Arg::new("VERSION").with_default("1.0"))
+            .then(Env::new("RUST_LOG", "debug"))
+            .then(Label::new("maintainer", "me"))
+            .then(WorkDir::new("/app"))
+            .then(Add::new("app.tar.gz", "/app"))
+            .then(Shell::new(["/bin/sh", "-c"]))
+            .then(User::new("app").with_group("app"))
+            .then(Expose::tcp(8080))
+            .then(Expose::udp(53))
+            .then(StopSignal::new("SIGTERM"))
+            .then(OnBuild::new(Run::new("echo child")))
+            .then(Cmd::exec(["--help"]));
+
+        let df_exp = r#"
+FROM alpine
+ARG VERSION=1.0
+ENV RUST_LOG=debug
+LABEL maintainer="me"
+WORKDIR /app
+ADD app.tar.gz /app
+SHELL ["/bin/sh", "-c"]
+USER app:app
+EXPOSE 8080
+EXPOSE 53/udp
+STOPSIGNAL SIGTERM
+ONBUILD RUN echo child
+CMD ["--help"]
+            "#;
+
+        assert_eq!(df.to_string().trim(), df_exp.trim());
+    }
+
+    #[test]
+    fn test_multi_stage() {
+        let df = DockerFile::new(From::image("rust").with_alias("builder"))
+            .then(Run::new("cargo build --release"))
+            .stage(From::image("alpine"))
+            .then(Copy::new("/target/release/app", "/app").from_stage("builder"))
+            .entry_point(["/app"]);
+
+        let df_exp = r#"
+FROM rust AS builder
+RUN cargo build --release
+
+FROM alpine
+COPY --from=builder /target/release/app /app
+ENTRYPOINT ["/app"]
+            "#;
+
+        assert_eq!(df.to_string().trim(), df_exp.trim());
+    }
+
+    #[test]
+    fn test_from() {
+        let from = From::image("alpine")
+            .with_tag("3.19")
+            .with_platform("linux/amd64")
+            .with_alias("base");
+        assert_eq!(
+            from.to_string(),
+            "FROM --platform=linux/amd64 alpine:3.19 AS base"
+        );
+    }
+
+    #[test]
+    fn test_escaping() {
+        let df = DockerFile::new(From::image("alpine"))
+            .then(Copy::new("my file", "/app/"))
+            .then(Volume::paths(["/data"]))
+            .then(Run::new(
+                "apk update && apk add --no-cache curl ca-certificates && echo 'a && b' > /tmp/x",
+            ))
+            .entry_point(["sh", "-c", "echo \"hi\\there\"\n"]);
+
+        let df_exp = r#"
+FROM alpine
+COPY ["my file", "/app/"]
+VOLUME /data
+RUN apk update \
+    && apk add --no-cache curl ca-certificates \
+    && echo 'a && b' > /tmp/x
+ENTRYPOINT ["sh", "-c", "echo \"hi\\there\"\n"]
+            "#;
+
+        assert_eq!(df.to_string().trim(), df_exp.trim());
+    }
+
+    proptest! {
+        #[test]
+        fn exec_form_round_trips(args in vec(any::<String>(), 0..5)) {
+            let rendered = Form::exec(&args).to_string();
+            prop_assert_eq!(crate::parser::json_array(&rendered), Some(args));
+        }
+
+        #[test]
+        fn docker_file_round_trips(
+            paths in vec("[a-zA-Z0-9_./ -]{1,12}", 2..4),
+            args in vec(any::<String>(), 1..4),
+            words in vec("[a-z]{1,12}", 1..16),
+            escape in proptest::option::of(prop_oneof![Just('\\'), Just('`')]),
+            syntax in proptest::option::of("docker/dockerfile:1(\\.[0-9]{1,2})?"),
+        ) {
+            let mut df = DockerFile::new(From::image("alpine"));
+            if let Some(x) = syntax {
+                df = df.with_directive("syntax", x);
+            }
+            if let Some(x) = escape {
+                df = df.with_directive("escape", x);
+            }
+            let df = df
+                .then(Copy::new(&paths[0], &paths[1]))
+                .then(Volume::paths(&paths))
+                .then(Run::new(words.join(" && ")))
+                .then(Cmd::exec(&args))
+                .entry_point(&args);
+
+            let rendered = df.to_string();
+            let parsed = DockerFile::parse(&rendered).unwrap();
+            prop_assert_eq!(parsed.to_string(), rendered);
+        }
+    }
+
+    #[test]
+    fn test_health_check() {
+        let check = HealthCheck::cmd(Form::shell("curl -f http://localhost/"))
+            .with_interval(Duration::from_secs(30))
+            .with_start_period(Duration::from_millis(1500))
+            .with_retries(3);
+        assert_eq!(
+            check.to_string(),
+            "HEALTHCHECK --interval=30s --start-period=1500ms --retries=3 CMD curl -f http://localhost/"
+        );
+        assert_eq!(HealthCheck::none().to_string(), "HEALTHCHECK NONE");
+        assert_eq!(Cmd::shell("echo hi").to_string(), "CMD echo hi");
+    }
+
+    #[test]
+    fn test_run_mounts() {
+        let run = Run::new("cargo build --release")
+            .with_mount(Mount::cache("/usr/local/cargo/registry"))
+            .with_mount(Mount::Cache {
+                target: "/src/target".to_string(),
+                id: Some("target".to_string()),
+                sharing: Some(Sharing::Locked),
+            })
+            .with_network(Network::None);
+        assert_eq!(
+            run.to_string(),
+            "RUN --mount=type=cache,target=/usr/local/cargo/registry \
+--mount=type=cache,target=/src/target,id=target,sharing=locked --network=none \
+cargo build --release"
+        );
+
+        let run = Run::exec(["make"])
+            .with_mount(Mount::bind("/src"))
+            .with_mount(Mount::secret("npmrc"));
+        assert_eq!(
+            run.to_string(),
+            r#"RUN --mount=type=bind,target=/src --mount=type=secret,id=npmrc ["make"]"#
+        );
+
+        let df = DockerFile::new(From::image("rust").with_tag("1.80"));
+        assert!(!df.needs_buildkit());
+        assert!(df.then(run).needs_buildkit());
+    }
+
+    #[test]
+    fn test_copy_options() {
+        let copy = Copy::sources(["Cargo.toml", "Cargo.lock"], "/src/")
+            .with_source("src")
+            .with_chown("app:app")
+            .with_chmod("0644")
+            .with_link(true);
+        assert_eq!(
+            copy.to_string(),
+            "COPY --chown=app:app --chmod=0644 --link Cargo.toml Cargo.lock src /src/"
+        );
+        assert!(copy.needs_buildkit());
+
+        let add = Add::new("app.tar.gz", "/opt").with_chown("1000:1000");
+        assert_eq!(add.to_string(), "ADD --chown=1000:1000 app.tar.gz /opt");
+        assert!(!add.needs_buildkit());
+        let heredoc = Copy::new("<<EOF", "/etc/motd").with_heredoc("EOF", "hi\n");
+        assert!(heredoc.needs_buildkit());
+    }
+
+    #[test]
+    #[should_panic(expected = "must end in /")]
+    fn test_copy_sources_into_file() {
+        Copy::new("a", "/app").with_source("b");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid --chmod")]
+    fn test_copy_invalid_chmod() {
+        Copy::new("a", "/app").with_chmod("rwx");
+    }
+
+    #[test]
+    fn test_derive_display() {
+        #[derive(Instruction)]
+        #[instruction(keyword = "COPY")]
+        struct CustomCopy {
+            #[instruction(flag = "chown")]
+            chown: Option<String>,
+            #[instruction(flag = "link")]
+            link: bool,
+            #[instruction(json_array)]
+            paths: Vec<String>,
+            #[instruction(skip)]
+            _note: &'static str,
+        }
+
+        let copy = CustomCopy {
+            chown: Some("app".to_string()),
+            link: true,
+            paths: vec!["my file".to_string(), "/app/".to_string()],
+            _note: "unrendered",
+        };
+        assert_eq!(
+            copy.to_string(),
+            r#"COPY --chown=app --link ["my file", "/app/"]"#
+        );
+        let df = DockerFile::new(From::image("alpine")).then(copy);
+        assert_eq!(
+            df.to_string(),
+            "FROM alpine\nCOPY --chown=app --link [\"my file\", \"/app/\"]\n"
+        );
+        assert_eq!(
+            Shell::new(["sh", "-c"]).to_string(),
+            r#"SHELL ["sh", "-c"]"#
+        );
+    }
+
+    #[test]
+    fn test_entry_point() {
+        let df = DockerFile::new(From::image("rust").with_alias("builder"))
+            .entry_point(["/old"])
+            .then(Run::new("cargo build"))
+            .entry_point(["/app"])
+            .stage(From::image("scratch"))
+            .then(Copy::new("/app", "/app").from_stage("builder"));
+        assert_eq!(
+            df.to_string(),
+            "FROM rust AS builder\nRUN cargo build\n\nFROM scratch\n\
+            COPY --from=builder /app /app\nENTRYPOINT [\"/app\"]\n"
+        );
+        assert_eq!(df.instructions_of::<EntryPoint>().count(), 1);
+        assert_eq!(df.locations().count(), 3);
+
+        // once removed, instructions go last again
+        let mut df = df;
+        df.retain(|x| !x.is::<EntryPoint>());
+        let df = df.then(User::new("app"));
+        assert!(df.to_string().ends_with("USER app\n"));
+    }
+
+    #[test]
+    fn test_edit_docker_file() {
+        let mut df = DockerFile::new(From::image("rust").with_tag("1.80").with_alias("builder"))
+            .then(Run::new("cargo build"))
+            .stage(From::image("alpine").with_tag("3.19"))
+            .then(Copy::new("/app", "/app").from_stage("builder"))
+            .then(Env::new("DEBUG", "1"))
+            .entry_point(["/app"]);
+
+        let keywords = |df: &DockerFile| df.instructions().map(|x| x.keyword()).join(" ");
+        assert_eq!(keywords(&df), "RUN COPY ENV ENTRYPOINT");
+        assert_eq!(df.instructions_of::<Run>().count(), 1);
+
+        assert!(df.insert_before(|x| x.is::<EntryPoint>(), User::new("app")));
+        assert!(!df.insert_before(|x| x.keyword() == "HEALTHCHECK", User::new("app")));
+        df.retain(|x| !x.is::<Env>());
+        let replaced = df.replace(
+            |x| x.downcast_ref::<Run>().is_some(),
+            |x| {
+                Run::new(format!(
+                    "{} --release",
+                    x.downcast_ref::<Run>().unwrap().cmd
+                ))
+            },
+        );
+        assert_eq!(replaced, 1);
+        for run in df.instructions_of_mut::<Run>() {
+            run.network = Some(Network::None);
+        }
+        *df.stage_named_mut("builder").unwrap().from_mut() =
+            From::image("rust").with_tag("1.81").with_alias("builder");
+
+        assert_eq!(keywords(&df), "RUN COPY USER ENTRYPOINT");
+        assert_eq!(
+            df.to_string(),
+            r#"FROM rust:1.81 AS builder
+RUN --network=none cargo build --release
+
+FROM alpine:3.19
+COPY --from=builder /app /app
+USER app
+ENTRYPOINT ["/app"]
+"#
+        );
+    }
+
+    #[test]
+    fn test_location_of() {
+        let line = line!() + 2;
+        let mut df = DockerFile::new(From::image("alpine"))
+            .then(Run::new("apk add curl"))
+            .then(User::new("app"))
+            .stage(From::image("alpine"))
+            .then(Run::new("apk add curl"))
+            .then(Env::new("A", "1"));
+        let location = df.location_of("RUN  apk add \\\n curl").unwrap();
+        assert_eq!((location.file(), location.line()), (file!(), line));
+        assert_eq!(df.location_of("USER app").unwrap().line(), line + 1);
+        assert!(df.location_of("RUN apk add wget").is_none());
+
+        // the same text added twice keeps both callers
+        let lines = |df: &DockerFile| {
+            df.locations()
+                .map(|(position, _, x)| (position, x.line() - line))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lines(&df),
+            [((0, 0), 0), ((0, 1), 1), ((1, 0), 3), ((1, 1), 4)]
+        );
+        df.retain(|x| !x.is::<User>());
+        df.stages_mut()[1]
+            .instructions_mut()
+            .nth(1)
+            .unwrap()
+            .downcast_mut::<Env>()
+            .unwrap()
+            .value = "2".to_string();
+        assert_eq!(lines(&df), [((0, 0), 0), ((1, 0), 3)]);
+    }
 }
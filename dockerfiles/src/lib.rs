@@ -1,3 +1,57 @@
+// lets `#[derive(Instruction)]` refer to `::dockerfiles` in this crate too
+extern crate self as dockerfiles;
+
+mod fragment;
+mod image_ref;
 mod instruction;
+mod lint;
+mod optimize;
+mod parser;
+#[cfg(feature = "serde")]
+mod serialize;
 
+/// ```compile_fail
+/// dockerfiles::dockerfile! { FROM alpine; RUNN echo hi };
+/// ```
+///
+/// ```compile_fail
+/// dockerfiles::dockerfile! { FROM alpine; COPY --chmodd=777 a b };
+/// ```
+///
+/// ```compile_fail
+/// dockerfiles::dockerfile! { FROM alpine; CMD ["sh" "-c"] };
+/// ```
+pub use docker_derive::dockerfile;
+pub use docker_derive::Instruction;
+pub use fragment::*;
+pub use image_ref::*;
 pub use instruction::*;
+pub use lint::*;
+pub use optimize::*;
+pub use parser::*;
+#[cfg(feature = "serde")]
+pub use serialize::*;
+
+/// Used by code generated by `#[derive(Instruction)]` and `dockerfile!`
+#[doc(hidden)]
+pub mod __derive {
+    pub fn fmt_json_array(
+        f: &mut std::fmt::Formatter<'_>,
+        items: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> std::fmt::Result {
+        crate::instruction::fmt_json_array(f, items)
+    }
+
+    pub fn dockerfile(
+        texts: &[&str],
+        values: Vec<(String, bool)>,
+        positions: &[(usize, usize)],
+    ) -> Result<crate::DockerFile, crate::ParseError> {
+        crate::parser::parse_interpolated(texts, values, positions)
+    }
+
+    pub fn literal_dockerfile(text: &str, positions: &[(usize, usize)]) -> crate::DockerFile {
+        crate::parser::parse_interpolated(&[text], Vec::new(), positions)
+            .unwrap_or_else(|e| panic!("invalid dockerfile!: {}", e))
+    }
+}
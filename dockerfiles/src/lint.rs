@@ -0,0 +1,279 @@
+use std::fmt::Display;
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Lint rules, their [Rule::id]s follow hadolint's where one exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    RelativeWorkDir,
+    UntaggedImage,
+    LatestTag,
+    AptGetNoCleanup,
+    AptGetNoConfirm,
+    UnknownCopyStage,
+    MultipleCmd,
+    MultipleEntryPoint,
+}
+
+impl Rule {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Rule::RelativeWorkDir => "DL3000",
+            Rule::UntaggedImage => "DL3006",
+            Rule::LatestTag => "DL3007",
+            Rule::AptGetNoCleanup => "DL3009",
+            Rule::AptGetNoConfirm => "DL3014",
+            Rule::UnknownCopyStage => "DL3022",
+            Rule::MultipleCmd => "DL4003",
+            Rule::MultipleEntryPoint => "DL4004",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Rule::RelativeWorkDir | Rule::UnknownCopyStage | Rule::MultipleEntryPoint => {
+                Severity::Error
+            }
+            Rule::UntaggedImage | Rule::LatestTag | Rule::AptGetNoConfirm | Rule::MultipleCmd => {
+                Severity::Warning
+            }
+            Rule::AptGetNoCleanup => Severity::Info,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub rule: Rule,
+    pub message: String,
+    /// Index of the stage the offending instruction is in
+    pub stage: usize,
+    /// The offending instruction as rendered
+    pub instruction: String,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.rule.severity()
+    }
+}
+
+impl DockerFile {
+    /// Check for common mistakes, see [Rule]
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (i, stage) in self.stages.iter().enumerate() {
+            let mut lint = |rule, message: String, instruction: &dyn Display| {
+                diagnostics.push(Diagnostic {
+                    rule,
+                    message,
+                    stage: i,
+                    instruction: instruction.to_string(),
+                })
+            };
+            self.lint_from(i, stage, &mut lint);
+
+            let mut cmds = Vec::new();
+            let mut entry_points = Vec::new();
+            for instr in stage.instructions() {
+                if let Some(x) = instr.downcast_ref::<WorkDir>() {
+                    if !x.path.starts_with(['/', '$']) {
+                        let message = format!("use an absolute WORKDIR instead of {}", x.path);
+                        lint(Rule::RelativeWorkDir, message, instr);
+                    }
+                } else if let Some(x) = instr.downcast_ref::<Run>() {
+                    if let Form::Shell(cmd) = &x.cmd {
+                        lint_apt_get(cmd, |rule, message| lint(rule, message, instr));
+                    }
+                } else if let Some(x) = instr.downcast_ref::<Copy>() {
+                    if let Some(from) = &x.stage {
+                        self.lint_copy_from(i, from, |rule, message| lint(rule, message, instr));
+                    }
+                } else if instr.is::<Cmd>() {
+                    cmds.push(instr.to_string());
+                } else if instr.is::<EntryPoint>() {
+                    entry_points.push(instr.to_string());
+                }
+            }
+            if cmds.len() > 1 {
+                let message = "only the last CMD in a stage takes effect".to_string();
+                lint(Rule::MultipleCmd, message, &cmds[0]);
+            }
+            if entry_points.len() > 1 {
+                let message = "only the last ENTRYPOINT in a stage takes effect".to_string();
+                lint(Rule::MultipleEntryPoint, message, &entry_points[0]);
+            }
+        }
+        diagnostics
+    }
+
+    /// [Self::lint], failing with the diagnostics if any of them is an error
+    pub fn validate(&self) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        let diagnostics = self.lint();
+        match diagnostics.iter().any(|x| x.severity() == Severity::Error) {
+            true => Err(diagnostics),
+            false => Ok(diagnostics),
+        }
+    }
+
+    fn is_stage_alias(&self, before: usize, name: &str) -> bool {
+        self.stages[..before]
+            .iter()
+            .any(|x| x.from.alias() == Some(name))
+    }
+
+    fn lint_from(&self, i: usize, stage: &Stage, mut lint: impl FnMut(Rule, String, &dyn Display)) {
+        let image = stage.from.image_ref();
+        let unchecked = image.to_string().contains('$')
+            || image.repository() == "scratch"
+            || image.registry().is_none() && self.is_stage_alias(i, image.repository());
+        if unchecked || image.digest().is_some() {
+            return;
+        }
+        match image.tag() {
+            None => lint(
+                Rule::UntaggedImage,
+                format!("pin a tag for {}", image),
+                &stage.from,
+            ),
+            Some("latest") => lint(
+                Rule::LatestTag,
+                format!("pin a tag other than latest for {}", image.name()),
+                &stage.from,
+            ),
+            Some(_) => {}
+        }
+    }
+
+    /// `--from` may name an earlier stage, a stage index or an image
+    fn lint_copy_from(&self, i: usize, from: &str, mut lint: impl FnMut(Rule, String)) {
+        let known = match from.parse::<usize>() {
+            Ok(index) => index < i,
+            // a bare name is almost always a misspelled stage, not an image
+            Err(_) => self.is_stage_alias(i, from) || from.contains(['/', ':', '@']),
+        };
+        if !known {
+            lint(
+                Rule::UnknownCopyStage,
+                format!("COPY --from={} does not refer to a previous stage", from),
+            );
+        }
+    }
+}
+
+fn lint_apt_get(cmd: &str, mut lint: impl FnMut(Rule, String)) {
+    let installs = cmd
+        .split(['&', ';', '|'])
+        .map(|x| x.split_whitespace().collect::<Vec<_>>())
+        .filter(|x| x.contains(&"apt-get") && x.contains(&"install"))
+        .collect::<Vec<_>>();
+    if installs.is_empty() {
+        return;
+    }
+    let confirmed = |words: &Vec<&str>| {
+        words.iter().any(|x| {
+            matches!(*x, "--yes" | "--assume-yes")
+                || x.starts_with('-') && !x.starts_with("--") && x.contains('y')
+        })
+    };
+    if !installs.iter().all(confirmed) {
+        let message = "use apt-get install -y to avoid prompting".to_string();
+        lint(Rule::AptGetNoConfirm, message);
+    }
+    if !cmd.contains("rm -rf /var/lib/apt/lists") {
+        let message = "remove /var/lib/apt/lists after apt-get install".to_string();
+        lint(Rule::AptGetNoCleanup, message);
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}[{}]: {} (stage {}: `{}`)",
+            self.severity(),
+            self.rule.id(),
+            self.message,
+            self.stage,
+            self.instruction
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    fn rules(df: &DockerFile) -> Vec<Rule> {
+        df.lint().into_iter().map(|x| x.rule).collect()
+    }
+
+    #[test]
+    fn test_lint_clean() {
+        let df = DockerFile::new(From::image("rust").with_tag("1.80").with_alias("builder"))
+            .then(WorkDir::new("/src"))
+            .then(Run::new(
+                "apt-get update && apt-get install -y musl-tools && rm -rf /var/lib/apt/lists/*",
+            ))
+            .stage(From::image("scratch"))
+            .then(Copy::new("/src/app", "/app").from_stage("builder"))
+            .then(Copy::new("/etc/nginx", "/etc/nginx").from_stage("nginx:1.27"))
+            .then(Copy::new("/src", "/src").from_stage("0"))
+            .entry_point(["/app"]);
+        assert_eq!(rules(&df), []);
+        assert!(df.validate().is_ok());
+    }
+
+    #[test]
+    fn test_lint_from() {
+        let df =
+            DockerFile::new(From::image("alpine")).stage(From::image("alpine").with_tag("latest"));
+        assert_eq!(rules(&df), [Rule::UntaggedImage, Rule::LatestTag]);
+        assert!(df.validate().is_ok());
+    }
+
+    #[test]
+    fn test_lint_instructions() {
+        let df = DockerFile::new(From::image("debian").with_tag("12"))
+            .then(WorkDir::new("app"))
+            .then(Run::new("apt-get update && apt-get install curl"))
+            .then(Copy::new("/out", "/out").from_stage("builder"))
+            .then(Cmd::exec(["a"]))
+            .then(Cmd::exec(["b"]))
+            .then(EntryPoint::exec(["sh"]))
+            .entry_point(["bash"]);
+        assert_eq!(
+            rules(&df),
+            [
+                Rule::RelativeWorkDir,
+                Rule::AptGetNoConfirm,
+                Rule::AptGetNoCleanup,
+                Rule::UnknownCopyStage,
+                Rule::MultipleCmd,
+                Rule::MultipleEntryPoint,
+            ]
+        );
+        let errors = df.validate().unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "error[DL3000]: use an absolute WORKDIR instead of app (stage 0: `WORKDIR app`)"
+        );
+    }
+}
@@ -0,0 +1,509 @@
+use std::fmt::Display;
+
+use itertools::Itertools;
+
+use crate::{instruction::split_and, *};
+
+/// Rewrites [DockerFile::optimize] can make
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Optimization {
+    /// Adjacent `RUN`s joined with `&&` into a single layer
+    MergeRuns,
+    /// Packages of an install command deduplicated and sorted
+    SortPackages,
+    /// A package install moved ahead of `COPY`/`ADD`s so that changing
+    /// the copied files doesn't invalidate its cached layer
+    HoistInstall,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub optimization: Optimization,
+    pub message: String,
+    /// Index of the stage the instruction is in
+    pub stage: usize,
+    /// The instruction as rendered after the change
+    pub instruction: String,
+}
+
+/// Package managers with the subcommands that install packages
+const INSTALLS: &[(&str, &str)] = &[
+    ("apk", "add"),
+    ("apt-get", "install"),
+    ("apt", "install"),
+    ("dnf", "install"),
+    ("microdnf", "install"),
+    ("yum", "install"),
+];
+
+/// Package manager flags whose value is the next word
+const VALUE_FLAGS: &[&str] = &[
+    "-c",
+    "-o",
+    "-p",
+    "-t",
+    "-x",
+    "-X",
+    "--disablerepo",
+    "--enablerepo",
+    "--exclude",
+    "--installroot",
+    "--releasever",
+    "--repo",
+    "--repository",
+    "--root",
+    "--target-release",
+    "--virtual",
+];
+
+/// Builtins whose effect would leak into the commands of a later `RUN`
+/// merged into the same shell
+const SHELL_STATE: &[&str] = &[
+    ".", "alias", "cd", "declare", "eval", "exec", "exit", "export", "hash", "local", "popd",
+    "pushd", "read", "readonly", "set", "shopt", "source", "trap", "typeset", "ulimit", "umask",
+    "unset",
+];
+
+/// Where package managers keep their configuration, files copied there
+/// may change what gets installed
+const SYSTEM_DIRS: &[&str] = &["/etc", "/usr", "/var", "/lib", "/lib64", "/bin", "/sbin"];
+
+impl DockerFile {
+    /// Reduce the number of layers and make them cache better, returning
+    /// what was changed, see [Optimization]. Only rewrites that don't
+    /// change the built image are made
+    pub fn optimize(&mut self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            let mut change = |optimization, message, instruction: &dyn Instruction| {
+                changes.push(Change {
+                    optimization,
+                    message,
+                    stage: i,
+                    instruction: instruction.to_string(),
+                })
+            };
+            hoist_installs(&mut stage.instrs, &mut change);
+            // `&&` means something else to other shells
+            if !stage.instructions().any(|x| x.is::<Shell>()) {
+                merge_runs(&mut stage.instrs, &mut change);
+            }
+            sort_packages(&mut stage.instrs, &mut change);
+        }
+        changes
+    }
+}
+
+fn hoist_installs(
+    instrs: &mut Vec<Box<dyn Instruction>>,
+    mut change: impl FnMut(Optimization, String, &dyn Instruction),
+) {
+    for i in 0..instrs.len() {
+        if !instrs[i]
+            .downcast_ref::<Run>()
+            .is_some_and(|x| is_install(x, &instrs[..i]))
+        {
+            continue;
+        }
+        let mut to = i;
+        while to > 0 && can_hoist_over(instrs, to - 1) {
+            to -= 1;
+        }
+        if to < i {
+            let instr = instrs.remove(i);
+            instrs.insert(to, instr);
+            let message = format!("moved a package install before {}", instrs[to + 1]);
+            change(Optimization::HoistInstall, message, &*instrs[to]);
+        }
+    }
+}
+
+/// Whether the `COPY` or `ADD` at `i` can't affect a package install
+fn can_hoist_over(instrs: &[Box<dyn Instruction>], i: usize) -> bool {
+    let instr = &instrs[i];
+    let to = match (instr.downcast_ref::<Copy>(), instr.downcast_ref::<Add>()) {
+        (Some(x), _) => &x.to,
+        (_, Some(x)) => &x.to,
+        _ => return false,
+    };
+    let Some(to) = destination(instrs, i, to) else {
+        return false;
+    };
+    let to = to.trim_end_matches('/');
+    !to.is_empty()
+        && !SYSTEM_DIRS
+            .iter()
+            .any(|dir| to == *dir || to.starts_with(&format!("{}/", dir)))
+}
+
+/// Absolute path of a destination relative to the `WORKDIR` at `i`, if
+/// it can be known without building
+fn destination(instrs: &[Box<dyn Instruction>], i: usize, to: &str) -> Option<String> {
+    if to.contains('$') {
+        return None;
+    }
+    if to.starts_with('/') {
+        return Some(to.to_string());
+    }
+    let workdir = instrs[..i]
+        .iter()
+        .rev()
+        .find_map(|x| x.downcast_ref::<WorkDir>())
+        .map_or("/", |x| x.path.as_str());
+    match workdir.starts_with('/') && !workdir.contains('$') {
+        true => Some(format!("{}/{}", workdir.trim_end_matches('/'), to)),
+        false => None,
+    }
+}
+
+fn merge_runs(
+    instrs: &mut Vec<Box<dyn Instruction>>,
+    mut change: impl FnMut(Optimization, String, &dyn Instruction),
+) {
+    let mut i = 0;
+    while i < instrs.len() {
+        let mut merged = 1;
+        while i + 1 < instrs.len() {
+            let (Some(run), Some(next)) = (
+                instrs[i].downcast_ref::<Run>(),
+                instrs[i + 1].downcast_ref::<Run>(),
+            ) else {
+                break;
+            };
+            let (Form::Shell(a), Form::Shell(b)) = (&run.cmd, &next.cmd) else {
+                break;
+            };
+            let mergeable = run.heredocs.is_empty()
+                && next.heredocs.is_empty()
+                && run.mounts == next.mounts
+                && run.network == next.network
+                && is_and_list(a)
+                && is_and_list(b);
+            if !mergeable {
+                break;
+            }
+            let cmd = format!("{} && {}", a, b);
+            instrs.remove(i + 1);
+            instrs[i].downcast_mut::<Run>().unwrap().cmd = Form::Shell(cmd);
+            merged += 1;
+        }
+        if merged > 1 {
+            let message = format!("merged {} RUNs into one layer", merged);
+            change(Optimization::MergeRuns, message, &*instrs[i]);
+        }
+        i += 1;
+    }
+}
+
+/// Whether `cmd` is a list of commands joined with `&&` that doesn't change
+/// the shell's state, so that it can be joined with another one
+fn is_and_list(cmd: &str) -> bool {
+    let mut quote = None;
+    let mut chars = cmd.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None | Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if q == c => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';' | '#' | '\n') => return false,
+            (None, '|') if chars.next_if_eq(&'|').is_some() => return false,
+            (None, '&') if chars.next_if_eq(&'&').is_none() => return false,
+            _ => {}
+        }
+    }
+    let (first, rest) = split_and(cmd);
+    quote.is_none() && !([first].into_iter().chain(rest).any(changes_shell_state))
+}
+
+/// Whether the command `part` of an `&&` list changes the state of the
+/// shell running it: a builtin such as `cd`, an assignment such as
+/// `FOO=1` (even one only for a command, to keep this simple) or a
+/// function definition
+fn changes_shell_state(part: &str) -> bool {
+    let mut words = part.split_whitespace();
+    let Some(first) = words.next() else {
+        return false;
+    };
+    let is_name = |x: &str| {
+        x.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    SHELL_STATE.contains(&first)
+        || first == "function"
+        || first.contains("()")
+        || words.next().is_some_and(|x| x.starts_with("()"))
+        || first.split_once('=').is_some_and(|(name, _)| is_name(name))
+}
+
+fn sort_packages(
+    instrs: &mut [Box<dyn Instruction>],
+    mut change: impl FnMut(Optimization, String, &dyn Instruction),
+) {
+    for instr in instrs {
+        let Some(Run {
+            cmd: Form::Shell(cmd),
+            heredocs,
+            ..
+        }) = instr.downcast_mut::<Run>()
+        else {
+            continue;
+        };
+        if !heredocs.is_empty() {
+            continue;
+        }
+        let (first, rest) = split_and(cmd);
+        let parts: Vec<_> = [first].into_iter().chain(rest).collect();
+        let sorted: Vec<_> = parts
+            .iter()
+            .map(|x| match install(x) {
+                Some(install) => install.sorted(),
+                None => x.to_string(),
+            })
+            .collect();
+        if sorted.iter().zip(&parts).any(|(a, b)| a != b) {
+            *cmd = sorted.join(" && ");
+            let message = "sorted and deduplicated installed packages".to_string();
+            change(Optimization::SortPackages, message, &**instr);
+        }
+    }
+}
+
+/// e.g. `DEBIAN_FRONTEND=noninteractive apt-get install -y curl git`
+struct Install<'a> {
+    /// Everything up to and including the subcommand, with the flags
+    command: Vec<&'a str>,
+    packages: Vec<&'a str>,
+}
+
+impl Install<'_> {
+    fn sorted(&self) -> String {
+        let packages = self.packages.iter().sorted().dedup();
+        self.command.iter().chain(packages).join(" ")
+    }
+}
+
+/// The packages installed by one `&&` separated part of a shell command,
+/// `None` if it isn't a plain install
+fn install(part: &str) -> Option<Install<'_>> {
+    if part.contains([
+        '\'', '"', '\\', '$', '`', ';', '|', '&', '<', '>', '(', '#', '*',
+    ]) {
+        return None;
+    }
+    let words: Vec<_> = part.split_whitespace().collect();
+    // leading `NAME=value` environment variables
+    let start = words.iter().position(|x| !x.contains('='))?;
+    let (manager, subcommand) = (*words.get(start)?, *words.get(start + 1)?);
+    if !INSTALLS.contains(&(manager, subcommand)) {
+        return None;
+    }
+    let mut command = words[..start + 2].to_vec();
+    let mut packages = Vec::new();
+    let mut args = words[start + 2..].iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            packages.push(*arg);
+            continue;
+        }
+        command.push(*arg);
+        if VALUE_FLAGS.contains(arg) {
+            command.push(*args.next()?);
+        }
+    }
+    Some(Install { command, packages })
+}
+
+/// Names of the files the `COPY`s and `ADD`s in `instrs` put in the image
+fn copied_names(instrs: &[Box<dyn Instruction>]) -> Vec<&str> {
+    let mut names = Vec::new();
+    for instr in instrs {
+        let (sources, to) = match (instr.downcast_ref::<Copy>(), instr.downcast_ref::<Add>()) {
+            (Some(x), _) => (&x.sources, &x.to),
+            (_, Some(x)) => (&x.sources, &x.to),
+            _ => continue,
+        };
+        names.extend(sources.iter().map(|x| file_name(x)));
+        if !to.ends_with('/') {
+            names.push(file_name(to));
+        }
+    }
+    names
+}
+
+fn file_name(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Whether `run` only installs packages, from the package manager's
+/// repositories rather than from files in the image, `before` being the
+/// instructions of its stage before it
+fn is_install(run: &Run, before: &[Box<dyn Instruction>]) -> bool {
+    let Form::Shell(cmd) = &run.cmd else {
+        return false;
+    };
+    let (first, rest) = split_and(cmd);
+    let parts: Vec<_> = [first].into_iter().chain(rest).collect();
+    let is_cleanup = |part: &str| {
+        let words: Vec<_> = part.split_whitespace().collect();
+        words.len() > 2
+            && words[..2] == ["rm", "-rf"]
+            && words[2..]
+                .iter()
+                .all(|x| x.starts_with("/var/lib/apt/lists") || x.starts_with("/var/cache/"))
+    };
+    let is_refresh = |part: &str| {
+        let words: Vec<_> = part.split_whitespace().collect();
+        matches!(
+            words[..],
+            ["apt-get" | "apt" | "apk", "update", ..] | ["dnf" | "yum", "clean", ..]
+        )
+    };
+    let copied = copied_names(before);
+    let is_local = |package: &str| {
+        package.contains('/')
+            || [".deb", ".rpm", ".apk"]
+                .iter()
+                .any(|x| package.ends_with(x))
+            || copied.contains(&package)
+    };
+    run.heredocs.is_empty()
+        && parts.iter().any(|x| install(x).is_some())
+        && parts.iter().all(|x| {
+            is_cleanup(x)
+                || is_refresh(x)
+                || install(x).is_some_and(|x| !x.packages.iter().any(|x| is_local(x)))
+        })
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (stage {}: `{}`)",
+            self.message, self.stage, self.instruction
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use crate::*;
+
+    fn optimizations(df: &mut DockerFile) -> Vec<Optimization> {
+        df.optimize().into_iter().map(|x| x.optimization).collect()
+    }
+
+    #[test]
+    fn test_merge_runs() {
+        let mut df = DockerFile::new(From::image("alpine").with_tag("3.19"))
+            .then(Run::new("mkdir /out"))
+            .then(Run::new("echo a > /out/a"))
+            .then(Run::new("echo 'b;c' > /out/b"))
+            .then(Env::new("A", "1"))
+            .then(Run::new("cd /out"))
+            .then(Run::new("ls"))
+            .then(Run::new("ls").with_network(Network::None))
+            .then(Run::exec(["ls"]));
+        assert_eq!(optimizations(&mut df), [Optimization::MergeRuns]);
+        assert_eq!(
+            df.stages()[0].instructions().join("\n"),
+            "RUN mkdir /out && echo a > /out/a && echo 'b;c' > /out/b
+ENV A=1
+RUN cd /out
+RUN ls
+RUN --network=none ls
+RUN [\"ls\"]"
+        );
+
+        let mut df = DockerFile::new(From::image("alpine").with_tag("3.19"))
+            .then(Run::new("false; true"))
+            .then(Run::new("true || false"))
+            .then(Run::new("true"));
+        assert_eq!(optimizations(&mut df), []);
+
+        // the variable and the function would be set for the next command
+        let mut df = DockerFile::new(From::image("alpine").with_tag("3.19"))
+            .then(Run::new("FOO=1"))
+            .then(Run::new("echo $FOO"))
+            .then(Run::new("greet() (echo hi)"))
+            .then(Run::new("mkdir /out && OUT=/out"))
+            .then(Run::new("greet"));
+        assert_eq!(optimizations(&mut df), []);
+
+        // `exec` replaces the shell and `eval` may do anything
+        let mut df = DockerFile::new(From::image("alpine").with_tag("3.19"))
+            .then(Run::new("exec 2>/dev/null"))
+            .then(Run::new("ls"))
+            .then(Run::new("eval \"$(cat /env)\""))
+            .then(Run::new("ls"));
+        assert_eq!(optimizations(&mut df), []);
+    }
+
+    #[test]
+    fn test_sort_packages() {
+        let mut df = DockerFile::new(From::image("debian").with_tag("12")).then(Run::new(
+            "apt-get update && apt-get install -y -o Dpkg::Use-Pty=0 git curl git \
+            && rm -rf /var/lib/apt/lists/*",
+        ));
+        let changes = df.optimize();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].optimization, Optimization::SortPackages);
+        assert_eq!(
+            df.stages()[0].instructions().join(""),
+            "RUN apt-get update \\
+    && apt-get install -y -o Dpkg::Use-Pty=0 curl git \\
+    && rm -rf /var/lib/apt/lists/*"
+        );
+    }
+
+    #[test]
+    fn test_hoist_installs() {
+        let mut df = DockerFile::new(From::image("alpine").with_tag("3.19"))
+            .then(WorkDir::new("/app"))
+            .then(Copy::new("test-binary", "."))
+            .then(Run::new("apk add --no-cache curl"))
+            .then(Run::new("apk add --no-cache bash"))
+            .then(Copy::new("repositories", "/etc/apk/repositories"))
+            .then(Run::new("apk add --no-cache jq"))
+            .then(Copy::new("pkg.apk", "/tmp/"))
+            .then(Run::new("apk add --no-cache /tmp/pkg.apk"))
+            .then(Copy::new("local.apk", "/tmp/"))
+            .then(Run::new("apk add --allow-untrusted local.apk"))
+            .then(Copy::new("tool", "/tmp/"))
+            .then(Run::new("apt-get install -y tool"))
+            .then(Run::new("./test-binary"));
+        let changes = df.optimize();
+        assert_eq!(
+            changes.iter().map(|x| x.optimization).collect_vec(),
+            [
+                Optimization::HoistInstall,
+                Optimization::HoistInstall,
+                Optimization::MergeRuns,
+                Optimization::MergeRuns,
+            ]
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            "moved a package install before COPY test-binary . (stage 0: `RUN apk add --no-cache curl`)"
+        );
+        assert_eq!(
+            df.stages()[0].instructions().join("\n"),
+            "WORKDIR /app
+RUN apk add --no-cache curl && apk add --no-cache bash
+COPY test-binary .
+COPY repositories /etc/apk/repositories
+RUN apk add --no-cache jq
+COPY pkg.apk /tmp/
+RUN apk add --no-cache /tmp/pkg.apk
+COPY local.apk /tmp/
+RUN apk add --allow-untrusted local.apk
+COPY tool /tmp/
+RUN apt-get install -y tool && ./test-binary"
+        );
+    }
+}
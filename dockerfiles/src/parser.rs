@@ -0,0 +1,1142 @@
+use std::{fmt::Display, ops::Range, str::FromStr, time::Duration};
+
+use docker_keywords::flags_of;
+
+use crate::*;
+
+/// Byte range into the parsed source
+pub type Span = Range<usize>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+    /// 1-based line of `span.start`
+    pub line: usize,
+    /// 1-based column (in chars) of `span.start`
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    NoFrom,
+    UnknownInstruction(String),
+    MissingArgument(String),
+    UnknownFlag(String),
+    InvalidJson(String),
+    InvalidArgument(String),
+    UnterminatedHeredoc(String),
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, span: Span, src: &str) -> Self {
+        let before = &src[..span.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .count()
+            + 1;
+        Self {
+            kind,
+            span,
+            line,
+            column,
+        }
+    }
+}
+
+impl DockerFile {
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        Parser::new(src).parse()
+    }
+}
+
+impl FromStr for DockerFile {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+type Flags = Vec<(String, String)>;
+
+struct Line<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+/// An instruction with its continuations joined, keyword split off
+struct Logical {
+    keyword: String,
+    args: String,
+    span: Span,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    lines: Vec<Line<'a>>,
+    next: usize,
+    escape: char,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        let mut start = 0;
+        let lines = src
+            .split_inclusive('\n')
+            .map(|raw| {
+                let text = raw.trim_end_matches('\n').trim_end_matches('\r');
+                let line = Line {
+                    text,
+                    span: start..start + text.len(),
+                };
+                start += raw.len();
+                line
+            })
+            .collect();
+        Self {
+            src,
+            lines,
+            next: 0,
+            escape: '\\',
+        }
+    }
+
+    fn parse(mut self) -> Result<DockerFile, ParseError> {
+        let directives = self.directives()?;
+        let mut args = Vec::new();
+        let mut df: Option<DockerFile> = None;
+
+        while let Some(logical) = self.logical() {
+            let heredocs = match logical.keyword.as_str() {
+                "RUN" | "COPY" | "ADD" => self.heredocs(&logical)?,
+                _ => Vec::new(),
+            };
+            let span = logical.span.start..self.lines[self.next - 1].span.end;
+            let logical = Logical { span, ..logical };
+
+            if logical.keyword == "FROM" {
+                let from = self.from(&logical)?;
+                df = Some(match df {
+                    Some(df) => df.stage(from),
+                    None => DockerFile::new(from),
+                });
+                continue;
+            }
+
+            match &mut df {
+                Some(df) => {
+                    let instr = self.instruction(&logical, heredocs)?;
+                    df.current_stage().instrs.extend(instr);
+                }
+                None if logical.keyword == "ARG" => args.extend(self.args(&logical)?),
+                None => return Err(self.error(ParseErrorKind::NoFrom, logical.span)),
+            }
+        }
+
+        let mut df =
+            df.ok_or_else(|| self.error(ParseErrorKind::NoFrom, self.src.len()..self.src.len()))?;
+        df.directives = directives;
+        df.args = args;
+        Ok(df)
+    }
+
+    fn error(&self, kind: ParseErrorKind, span: Span) -> ParseError {
+        ParseError::new(kind, span, self.src)
+    }
+
+    /// `# name=value` lines at the very top of the file, stopping at
+    /// the first line that isn't one
+    fn directives(&mut self) -> Result<Vec<(String, String)>, ParseError> {
+        let mut directives: Vec<(String, String)> = Vec::new();
+        while let Some(line) = self.lines.get(self.next) {
+            let Some((name, value)) = line
+                .text
+                .trim()
+                .strip_prefix('#')
+                .and_then(|x| x.split_once('='))
+            else {
+                break;
+            };
+            let (name, value) = (name.trim().to_lowercase(), value.trim());
+            let known = matches!(name.as_str(), "syntax" | "escape" | "check");
+            if !known || directives.iter().any(|(x, _)| *x == name) {
+                break;
+            }
+            if name == "escape" {
+                self.escape = match value {
+                    "\\" => '\\',
+                    "`" => '`',
+                    _ => {
+                        let kind = ParseErrorKind::InvalidArgument(format!(
+                            "invalid escape character {:?}",
+                            value
+                        ));
+                        return Err(self.error(kind, line.span.clone()));
+                    }
+                };
+            }
+            directives.push((name, value.to_string()));
+            self.next += 1;
+        }
+        Ok(directives)
+    }
+
+    fn is_blank_or_comment(text: &str) -> bool {
+        let text = text.trim_start();
+        text.is_empty() || text.starts_with('#')
+    }
+
+    /// Next instruction with line continuations joined, skipping blank
+    /// lines and comments (also those in the middle of a continuation)
+    fn logical(&mut self) -> Option<Logical> {
+        while Self::is_blank_or_comment(self.lines.get(self.next)?.text) {
+            self.next += 1;
+        }
+        let start = self.lines[self.next].span.start;
+        let mut text = String::new();
+        while let Some(line) = self.lines.get(self.next) {
+            self.next += 1;
+            if !text.is_empty() && Self::is_blank_or_comment(line.text) {
+                continue;
+            }
+            match line.text.trim_end().strip_suffix(self.escape) {
+                Some(x) => text.push_str(x),
+                None => {
+                    text.push_str(line.text);
+                    break;
+                }
+            }
+        }
+        let text = text.trim();
+        let (keyword, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        Some(Logical {
+            keyword: keyword.to_uppercase(),
+            args: args.trim().to_string(),
+            span: start..self.lines[self.next - 1].span.end,
+        })
+    }
+
+    fn heredocs(&mut self, logical: &Logical) -> Result<Vec<Heredoc>, ParseError> {
+        if logical.args.starts_with('[') {
+            return Ok(Vec::new());
+        }
+        heredoc_markers(&logical.args)
+            .into_iter()
+            .map(|(delimiter, strip_tabs)| {
+                let mut body = String::new();
+                loop {
+                    let Some(line) = self.lines.get(self.next) else {
+                        let kind = ParseErrorKind::UnterminatedHeredoc(delimiter);
+                        return Err(self.error(kind, logical.span.clone()));
+                    };
+                    self.next += 1;
+                    let end = match strip_tabs {
+                        true => line.text.trim_start_matches('\t'),
+                        false => line.text,
+                    };
+                    if end == delimiter {
+                        return Ok(Heredoc { delimiter, body });
+                    }
+                    body.push_str(line.text);
+                    body.push('\n');
+                }
+            })
+            .collect()
+    }
+
+    fn instruction(
+        &self,
+        logical: &Logical,
+        heredocs: Vec<Heredoc>,
+    ) -> Result<Vec<Box<dyn Instruction>>, ParseError> {
+        let span = &logical.span;
+        let args = logical.args.as_str();
+        let err = |kind| Err(self.error(kind, span.clone()));
+        if args.is_empty() {
+            return err(ParseErrorKind::MissingArgument(logical.keyword.clone()));
+        }
+
+        let instr: Box<dyn Instruction> = match logical.keyword.as_str() {
+            "RUN" => {
+                let (flags, rest) = self.flags(logical)?;
+                let mut run = Run::with_form(form(rest));
+                run.heredocs = heredocs;
+                for (name, value) in flags {
+                    let invalid = |reason| {
+                        let kind = ParseErrorKind::InvalidArgument(reason);
+                        self.error(kind, span.clone())
+                    };
+                    run = match name.as_str() {
+                        "mount" => run.with_mount(mount(&value).map_err(invalid)?),
+                        _ => run.with_network(match value.as_str() {
+                            "default" => Network::Default,
+                            "none" => Network::None,
+                            "host" => Network::Host,
+                            x => return Err(invalid(format!("unknown network {}", x))),
+                        }),
+                    };
+                }
+                Box::new(run)
+            }
+            "CMD" => Box::new(Cmd { cmd: form(args) }),
+            "ENTRYPOINT" => Box::new(EntryPoint { cmd: form(args) }),
+            "COPY" => {
+                let (flags, rest) = self.flags(logical)?;
+                let (sources, to) = self.paths(logical, rest)?;
+                let mut copy = Copy::sources(sources, to);
+                for (name, value) in flags {
+                    match name.as_str() {
+                        "from" => copy.stage = Some(value),
+                        "chown" => copy.chown = Some(self.chown(logical, value)?),
+                        "chmod" => copy.chmod = Some(self.chmod(logical, value)?),
+                        "link" => copy.link = self.bool_flag(logical, &value)?,
+                        _ => copy.parents = self.bool_flag(logical, &value)?,
+                    }
+                }
+                copy.heredocs = heredocs;
+                Box::new(copy)
+            }
+            "ADD" => {
+                let (flags, rest) = self.flags(logical)?;
+                let (sources, to) = self.paths(logical, rest)?;
+                let mut add = Add::sources(sources, to);
+                for (name, value) in flags {
+                    match name.as_str() {
+                        "chown" => add.chown = Some(self.chown(logical, value)?),
+                        "chmod" => add.chmod = Some(self.chmod(logical, value)?),
+                        _ => add.link = self.bool_flag(logical, &value)?,
+                    }
+                }
+                add.heredocs = heredocs;
+                Box::new(add)
+            }
+            "VOLUME" => Box::new(Volume::paths(
+                json_array(args).unwrap_or_else(|| split_whitespace(args)),
+            )),
+            "SHELL" => match json_array(args) {
+                Some(cmds) => Box::new(Shell::new(cmds)),
+                None => return err(ParseErrorKind::InvalidJson(args.to_string())),
+            },
+            "WORKDIR" => Box::new(WorkDir::new(args)),
+            "STOPSIGNAL" => Box::new(StopSignal::new(args)),
+            "USER" => Box::new(match args.split_once(':') {
+                Some((user, group)) => User::new(user).with_group(group),
+                None => User::new(args),
+            }),
+            "HEALTHCHECK" => Box::new(self.health_check(logical)?),
+            "ONBUILD" => {
+                let text = args.trim();
+                let (keyword, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+                let inner = Logical {
+                    keyword: keyword.to_uppercase(),
+                    args: args.trim().to_string(),
+                    span: span.clone(),
+                };
+                if matches!(inner.keyword.as_str(), "ONBUILD" | "FROM") {
+                    let kind = ParseErrorKind::InvalidArgument(format!(
+                        "{} is not allowed in ONBUILD",
+                        inner.keyword
+                    ));
+                    return err(kind);
+                }
+                return self
+                    .instruction(&inner, Vec::new())
+                    .map(|x| x.into_iter().map(|x| Box::new(OnBuild { instr: x }) as _))
+                    .map(Iterator::collect);
+            }
+            "ENV" => return self.env(logical),
+            "LABEL" => {
+                let labels = self.key_values(logical, false)?;
+                return Ok(labels
+                    .into_iter()
+                    .map(|(k, v)| Box::new(Label::new(k, v)) as _)
+                    .collect());
+            }
+            "ARG" => {
+                let args = self.args(logical)?;
+                return Ok(args.into_iter().map(|x| Box::new(x) as _).collect());
+            }
+            "EXPOSE" => return self.expose(logical),
+            keyword => return err(ParseErrorKind::UnknownInstruction(keyword.to_string())),
+        };
+        Ok(vec![instr])
+    }
+
+    fn from(&self, logical: &Logical) -> Result<From, ParseError> {
+        let (flags, rest) = self.flags(logical)?;
+        let words = split_whitespace(rest);
+        let (image, alias) = match words.as_slice() {
+            [image] => (image, None),
+            [image, as_, alias] if as_.eq_ignore_ascii_case("as") => (image, Some(alias)),
+            [] => {
+                let kind = ParseErrorKind::MissingArgument(logical.keyword.clone());
+                return Err(self.error(kind, logical.span.clone()));
+            }
+            _ => {
+                let kind = ParseErrorKind::InvalidArgument(rest.to_string());
+                return Err(self.error(kind, logical.span.clone()));
+            }
+        };
+        let image = ImageRef::parse(image).map_err(|e| {
+            let kind = ParseErrorKind::InvalidArgument(e.to_string());
+            self.error(kind, logical.span.clone())
+        })?;
+
+        let mut from = From::new(image);
+        if let Some((_, platform)) = flags.into_iter().next() {
+            from = from.with_platform(platform);
+        }
+        if let Some(alias) = alias {
+            from = from.with_alias(alias);
+        }
+        Ok(from)
+    }
+
+    /// Leading `--name=value` flags, erroring on any the instruction
+    /// doesn't take, see [docker_keywords::INSTRUCTIONS]
+    fn flags<'b>(&self, logical: &'b Logical) -> Result<(Flags, &'b str), ParseError> {
+        let known = flags_of(&logical.keyword).unwrap_or_default();
+        let mut flags = Vec::new();
+        let mut rest = logical.args.as_str();
+        while let Some(flag) = rest.strip_prefix("--") {
+            let (flag, after) = flag.split_once(char::is_whitespace).unwrap_or((flag, ""));
+            let (name, value) = flag.split_once('=').unwrap_or((flag, ""));
+            if !known.contains(&name) {
+                let kind = ParseErrorKind::UnknownFlag(format!("--{}", name));
+                return Err(self.error(kind, logical.span.clone()));
+            }
+            flags.push((name.to_string(), value.to_string()));
+            rest = after.trim_start();
+        }
+        Ok((flags, rest))
+    }
+
+    fn chown(&self, logical: &Logical, owner: String) -> Result<String, ParseError> {
+        if is_chown(&owner) {
+            return Ok(owner);
+        }
+        let kind = ParseErrorKind::InvalidArgument(format!("invalid --chown {}", owner));
+        Err(self.error(kind, logical.span.clone()))
+    }
+
+    fn chmod(&self, logical: &Logical, mode: String) -> Result<String, ParseError> {
+        if is_chmod(&mode) {
+            return Ok(mode);
+        }
+        let kind = ParseErrorKind::InvalidArgument(format!("invalid --chmod {}", mode));
+        Err(self.error(kind, logical.span.clone()))
+    }
+
+    /// Sources and destination of a `COPY` or `ADD`
+    fn paths(&self, logical: &Logical, args: &str) -> Result<(Vec<String>, String), ParseError> {
+        let mut paths = json_array(args).unwrap_or_else(|| split_whitespace(args));
+        let kind = match paths.len() {
+            0 | 1 => ParseErrorKind::MissingArgument(logical.keyword.clone()),
+            2 => {
+                let to = paths.pop().unwrap();
+                return Ok((paths, to));
+            }
+            _ if paths
+                .last()
+                .is_some_and(|x| x.ends_with('/') || x.contains('$')) =>
+            {
+                let to = paths.pop().unwrap();
+                return Ok((paths, to));
+            }
+            _ => ParseErrorKind::InvalidArgument(format!(
+                "{} with several sources needs a destination ending in /",
+                logical.keyword
+            )),
+        };
+        Err(self.error(kind, logical.span.clone()))
+    }
+
+    /// `--link` or `--link=true|false`
+    fn bool_flag(&self, logical: &Logical, value: &str) -> Result<bool, ParseError> {
+        match value {
+            "" | "true" => Ok(true),
+            "false" => Ok(false),
+            x => {
+                let kind = ParseErrorKind::InvalidArgument(format!("invalid flag value {}", x));
+                Err(self.error(kind, logical.span.clone()))
+            }
+        }
+    }
+
+    fn env(&self, logical: &Logical) -> Result<Vec<Box<dyn Instruction>>, ParseError> {
+        let args = logical.args.as_str();
+        let (first, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        // legacy `ENV key value with spaces`
+        if !first.contains('=') {
+            let value = self.words(logical, rest.trim())?.join(" ");
+            return Ok(vec![Box::new(Env::new(first, value))]);
+        }
+        let envs = self.key_values(logical, true)?;
+        Ok(envs
+            .into_iter()
+            .map(|(k, v)| Box::new(Env::new(k, v)) as _)
+            .collect())
+    }
+
+    fn key_values(
+        &self,
+        logical: &Logical,
+        is_env: bool,
+    ) -> Result<Vec<(String, String)>, ParseError> {
+        self.words(logical, &logical.args)?
+            .into_iter()
+            .map(|word| match word.split_once('=') {
+                Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+                _ => {
+                    let what = if is_env { "ENV" } else { "LABEL" };
+                    let kind =
+                        ParseErrorKind::InvalidArgument(format!("{} expects key=value", what));
+                    Err(self.error(kind, logical.span.clone()))
+                }
+            })
+            .collect()
+    }
+
+    fn args(&self, logical: &Logical) -> Result<Vec<Arg>, ParseError> {
+        Ok(self
+            .words(logical, &logical.args)?
+            .into_iter()
+            .map(|word| match word.split_once('=') {
+                Some((name, default)) => Arg::new(name).with_default(default),
+                None => Arg::new(word),
+            })
+            .collect())
+    }
+
+    fn expose(&self, logical: &Logical) -> Result<Vec<Box<dyn Instruction>>, ParseError> {
+        split_whitespace(&logical.args)
+            .into_iter()
+            .map(|word| {
+                let (port, proto) = word.split_once('/').unwrap_or((&word, "tcp"));
+                let expose = match (ports(port), proto.to_lowercase().as_str()) {
+                    (Some(port), "tcp") => Expose::tcp(port),
+                    (Some(port), "udp") => Expose::udp(port),
+                    _ => {
+                        let kind =
+                            ParseErrorKind::InvalidArgument(format!("invalid port {}", word));
+                        return Err(self.error(kind, logical.span.clone()));
+                    }
+                };
+                Ok(Box::new(expose) as _)
+            })
+            .collect()
+    }
+
+    fn health_check(&self, logical: &Logical) -> Result<HealthCheck, ParseError> {
+        if logical.args.eq_ignore_ascii_case("none") {
+            return Ok(HealthCheck::none());
+        }
+        let (flags, rest) = self.flags(logical)?;
+        let Some(cmd) = rest
+            .split_once(char::is_whitespace)
+            .filter(|(cmd, _)| cmd.eq_ignore_ascii_case("cmd"))
+            .map(|(_, cmd)| cmd.trim())
+        else {
+            let kind = ParseErrorKind::MissingArgument("HEALTHCHECK CMD".to_string());
+            return Err(self.error(kind, logical.span.clone()));
+        };
+
+        let mut check = HealthCheck::cmd(form(cmd));
+        for (name, value) in flags {
+            let invalid = || {
+                let kind = ParseErrorKind::InvalidArgument(format!("--{}={}", name, value));
+                self.error(kind, logical.span.clone())
+            };
+            if name == "retries" {
+                check.retries = Some(value.parse().map_err(|_| invalid())?);
+                continue;
+            }
+            let d = Some(parse_duration(&value).ok_or_else(invalid)?);
+            match name.as_str() {
+                "interval" => check.interval = d,
+                "timeout" => check.timeout = d,
+                "start-period" => check.start_period = d,
+                _ => check.start_interval = d,
+            }
+        }
+        Ok(check)
+    }
+
+    /// Split on whitespace honouring quotes and escapes, the way
+    /// `ENV`, `LABEL` and `ARG` read their arguments
+    fn words(&self, logical: &Logical, args: &str) -> Result<Vec<String>, ParseError> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = args.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => words.extend(word.take()),
+                '\'' | '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some(x) if x == c => break,
+                            Some(x) if x == self.escape && c == '"' => match chars.next() {
+                                Some(x) if x == '"' || x == self.escape => word.push(x),
+                                Some(x) => word.extend([self.escape, x]),
+                                None => word.push(self.escape),
+                            },
+                            Some(x) => word.push(x),
+                            None => {
+                                let kind = ParseErrorKind::InvalidArgument(format!(
+                                    "unterminated quote in {}",
+                                    args
+                                ));
+                                return Err(self.error(kind, logical.span.clone()));
+                            }
+                        }
+                    }
+                }
+                c if c == self.escape => {
+                    let word = word.get_or_insert_with(String::new);
+                    word.extend(chars.next());
+                }
+                c => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+        Ok(words)
+    }
+}
+
+fn split_whitespace(args: &str) -> Vec<String> {
+    args.split_whitespace().map(String::from).collect()
+}
+
+/// JSON array means exec form, anything else (including malformed
+/// JSON, like Docker does) is shell form
+fn form(args: &str) -> Form {
+    match json_array(args) {
+        Some(cmds) => Form::Exec(cmds),
+        None => Form::Shell(args.to_string()),
+    }
+}
+
+/// `type=cache,target=/root/.cargo` and friends, `type` defaults to bind
+fn mount(value: &str) -> Result<Mount, String> {
+    let mut options: Vec<(&str, &str)> = value
+        .split(',')
+        .map(|x| x.split_once('=').unwrap_or((x, "true")))
+        .collect();
+    let mut take = |keys: &[&str]| {
+        let i = options.iter().position(|(k, _)| keys.contains(k))?;
+        Some(options.remove(i).1.to_string())
+    };
+    let flag = |x: Option<String>| match x.as_deref() {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(x) => Err(format!("invalid mount option value {}", x)),
+    };
+
+    let target = take(&["target", "dst", "destination"]);
+    let missing_target = || format!("mount {} without target", value);
+    let mount = match take(&["type"]).as_deref().unwrap_or("bind") {
+        "cache" => Mount::Cache {
+            target: target.ok_or_else(missing_target)?,
+            id: take(&["id"]),
+            sharing: match take(&["sharing"]).as_deref() {
+                None => None,
+                Some("shared") => Some(Sharing::Shared),
+                Some("private") => Some(Sharing::Private),
+                Some("locked") => Some(Sharing::Locked),
+                Some(x) => return Err(format!("unknown cache sharing {}", x)),
+            },
+        },
+        "bind" => Mount::Bind {
+            target: target.ok_or_else(missing_target)?,
+            source: take(&["source", "src"]),
+            from: take(&["from"]),
+            rw: flag(take(&["rw", "readwrite"]))?,
+        },
+        "secret" => Mount::Secret {
+            id: take(&["id"]).ok_or_else(|| format!("mount {} without id", value))?,
+            target,
+            required: flag(take(&["required"]))?,
+        },
+        x => return Err(format!("unsupported mount type {}", x)),
+    };
+    match options.first() {
+        Some((k, _)) => Err(format!("unsupported mount option {}", k)),
+        None => Ok(mount),
+    }
+}
+
+/// `<<EOF`, `<<-EOF`, `<<"EOF"` markers outside of quotes, paired
+/// with whether leading tabs are stripped from the terminator
+fn heredoc_markers(args: &str) -> Vec<(String, bool)> {
+    let mut markers = Vec::new();
+    let mut quote = None;
+    let mut rest = args;
+    while let Some(c) = rest.chars().next() {
+        match (quote, c) {
+            (Some(q), c) if q == c => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '<') if rest.starts_with("<<") && !rest.starts_with("<<<") => {
+                let marker = &rest[2..];
+                let (strip_tabs, marker) = match marker.strip_prefix('-') {
+                    Some(x) => (true, x),
+                    None => (false, marker),
+                };
+                let (quoted, marker) = match marker.chars().next() {
+                    Some(q @ ('\'' | '"')) => (Some(q), &marker[1..]),
+                    _ => (None, marker),
+                };
+                let len = marker
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(marker.len());
+                let closed = quoted.is_none_or(|q| marker[len..].starts_with(q));
+                if len > 0 && closed {
+                    markers.push((marker[..len].to_string(), strip_tabs));
+                    rest = &marker[len + quoted.map_or(0, |_| 1)..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    markers
+}
+
+/// A JSON array of strings, `None` if `args` is anything else
+pub(crate) fn json_array(args: &str) -> Option<Vec<String>> {
+    let mut chars = args.trim().strip_prefix('[')?.chars().peekable();
+    let mut items = Vec::new();
+    let skip_ws = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+
+    skip_ws(&mut chars);
+    if chars.next_if_eq(&']').is_none() {
+        loop {
+            skip_ws(&mut chars);
+            if chars.next()? != '"' {
+                return None;
+            }
+            let mut item = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => item.push(match chars.next()? {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => {
+                            let hex: String = (0..4).filter_map(|_| chars.next()).collect();
+                            char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                        }
+                        c @ ('"' | '\\' | '/') => c,
+                        _ => return None,
+                    }),
+                    c if c.is_control() => return None,
+                    c => item.push(c),
+                }
+            }
+            items.push(item);
+            skip_ws(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                ']' => break,
+                _ => return None,
+            }
+        }
+    }
+    skip_ws(&mut chars);
+    chars.next().is_none().then_some(items)
+}
+
+/// Parse the text of a `dockerfile!` with the values of its `{expr}`s
+/// put back in between `texts`, JSON array elements quoted. `positions`
+/// are those of its instructions in the macro call, which errors point
+/// at instead of the generated text, which has one instruction per line
+pub(crate) fn parse_interpolated(
+    texts: &[&str],
+    values: Vec<(String, bool)>,
+    positions: &[(usize, usize)],
+) -> Result<DockerFile, ParseError> {
+    let at = |e: ParseError| {
+        let (line, column) = positions.get(e.line - 1).copied().unwrap_or((0, 0));
+        ParseError { line, column, ..e }
+    };
+    let invalid = |src: &str, message: String| {
+        let start = src.rfind('\n').map_or(0, |x| x + 1);
+        let kind = ParseErrorKind::InvalidArgument(message);
+        at(ParseError::new(kind, start..src.len(), src))
+    };
+    let mut src = texts.first().copied().unwrap_or_default().to_string();
+    for ((value, json), text) in values.into_iter().zip(&texts[1..]) {
+        if json {
+            src += &json_string(&value);
+        } else if value.contains(['\n', '\r']) {
+            let message = format!("interpolated {:?} would start a new instruction", value);
+            return Err(invalid(&src, message));
+        } else {
+            src += &value;
+        }
+        src += text;
+    }
+    // the macro's own tokens can't end a line in `\`, so it is a value's
+    // and would join the next instruction to this one
+    let mut start = 0;
+    for line in src.split_inclusive('\n') {
+        if line.trim_end().ends_with('\\') {
+            let message = format!("interpolated line continuation in {:?}", line.trim());
+            return Err(invalid(&src[..start + line.len() - 1], message));
+        }
+        start += line.len();
+    }
+    DockerFile::parse(&src).map_err(at)
+}
+
+/// `80`, `8000-8010`, or a variable such as `$PORT` or `${PORT}`
+fn ports(word: &str) -> Option<Ports> {
+    if word.starts_with('$') {
+        return Some(Ports::Variable(word.to_string()));
+    }
+    match word.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then_some(Ports::Range(start, end))
+        }
+        None => word.parse().ok().map(Ports::Port),
+    }
+}
+
+/// Go's `time.ParseDuration`, e.g. `1m30s` or `500ms`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let n: f64 = rest[..len].parse().ok()?;
+        rest = &rest[len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let secs = match &rest[..unit_len] {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total = total.checked_add(Duration::try_from_secs_f64(n * secs).ok()?)?;
+        rest = &rest[unit_len..];
+    }
+    (!s.is_empty()).then_some(total)
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::NoFrom => write!(f, "expected FROM before any instruction"),
+            ParseErrorKind::UnknownInstruction(x) => write!(f, "unknown instruction {}", x),
+            ParseErrorKind::MissingArgument(x) => write!(f, "{} requires an argument", x),
+            ParseErrorKind::UnknownFlag(x) => write!(f, "unknown flag {}", x),
+            ParseErrorKind::InvalidJson(x) => write!(f, "expected a JSON array, found {}", x),
+            ParseErrorKind::InvalidArgument(x) => write!(f, "invalid argument: {}", x),
+            ParseErrorKind::UnterminatedHeredoc(x) => write!(f, "heredoc {} is never closed", x),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let src = r#"# syntax=docker/dockerfile:1
+ARG VERSION=3.19
+FROM rust AS builder
+WORKDIR /src
+RUN cargo build --release
+RUN ["cargo", "test"]
+
+FROM --platform=$BUILDPLATFORM alpine:${VERSION}
+ENV GREETING="hello world"
+LABEL org.opencontainers.image.title="runner"
+COPY --from=builder /src/target/app /app
+RUN <<EOF
+apk add curl
+echo done
+EOF
+VOLUME /data /cache
+USER app:app
+EXPOSE 8080
+EXPOSE 53/udp
+HEALTHCHECK --interval=30s --retries=3 CMD curl -f http://localhost/
+ONBUILD RUN echo child
+STOPSIGNAL SIGTERM
+SHELL ["/bin/sh", "-c"]
+ENTRYPOINT ["/app"]
+"#;
+        let df = DockerFile::parse(src).unwrap();
+        assert_eq!(df.to_string(), src);
+    }
+
+    #[test]
+    fn test_parse_normalizes() {
+        let src = r#"
+# a comment
+from alpine as base
+env A=1 B="two words"
+run apk add \
+    # comments in continuations are dropped
+    curl \
+    jq
+copy ["src", "/dst"]
+cmd echo hi
+healthcheck NONE
+"#;
+        let df = DockerFile::parse(src).unwrap();
+        let df_exp = r#"
+FROM alpine AS base
+ENV A=1
+ENV B="two words"
+RUN apk add     curl     jq
+COPY src /dst
+CMD echo hi
+HEALTHCHECK NONE
+            "#;
+        assert_eq!(df.to_string().trim(), df_exp.trim());
+    }
+
+    #[test]
+    fn test_parse_heredoc() {
+        let src = "FROM alpine\nRUN <<-EOF cat > /greeting\n\thello\n\tEOF\nCOPY <<CONF /etc/app.conf\nkey=value\nCONF\n";
+        let df = DockerFile::parse(src).unwrap();
+        assert_eq!(
+            df.to_string(),
+            "FROM alpine\nRUN <<-EOF cat > /greeting\n\thello\nEOF\nCOPY <<CONF /etc/app.conf\nkey=value\nCONF\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_escape_directive() {
+        let src = "# escape=`\nFROM alpine\nRUN echo a `\n  b\n";
+        let df = DockerFile::parse(src).unwrap();
+        assert_eq!(df.to_string(), "# escape=`\nFROM alpine\nRUN echo a   b\n");
+
+        let long = [
+            "apt-get update",
+            "apt-get install -y --no-install-recommends curl ca-certificates",
+            "rm -rf /var/lib/apt/lists/*",
+        ];
+        let src = format!("# escape=`\nFROM debian\nRUN {}\n", long.join(" && "));
+        let rendered = DockerFile::parse(&src).unwrap().to_string();
+        assert!(
+            rendered.contains(" `\n    && apt-get install"),
+            "{}",
+            rendered
+        );
+        assert_eq!(DockerFile::parse(&rendered).unwrap().to_string(), rendered);
+    }
+
+    #[test]
+    fn test_parse_expose() {
+        let src = "FROM alpine\nEXPOSE 8000-8010\nEXPOSE ${PORT}/udp\nEXPOSE $PORT\n";
+        let df = DockerFile::parse(src).unwrap();
+        assert_eq!(df.to_string(), src);
+        let ports: Vec<_> = df.instructions_of::<Expose>().map(|x| &x.port).collect();
+        assert_eq!(
+            ports,
+            [
+                &Ports::Range(8000, 8010),
+                &Ports::Variable("${PORT}".to_string()),
+                &Ports::Variable("$PORT".to_string()),
+            ]
+        );
+
+        for word in ["8010-8000", "http", "80/sctp"] {
+            let err = DockerFile::parse(&format!("FROM alpine\nEXPOSE {}\n", word))
+                .err()
+                .unwrap();
+            assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = DockerFile::parse("FROM alpine\n\nFOO bar\n").err().unwrap();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnknownInstruction("FOO".to_string())
+        );
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(err.span, 13..20);
+
+        let err = DockerFile::parse("RUN echo hi\n").err().unwrap();
+        assert_eq!(err.kind, ParseErrorKind::NoFrom);
+
+        let err = DockerFile::parse("FROM alpine\nRUN <<EOF\necho\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnterminatedHeredoc("EOF".to_string())
+        );
+
+        let err = DockerFile::parse("FROM alpine\nSHELL sh -c\n")
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidJson(_)));
+
+        let err = DockerFile::parse("FROM alpine\nCOPY --exclude=*.md a b\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnknownFlag("--exclude".to_string())
+        );
+
+        let err = DockerFile::parse("FROM alpine\nCOPY a b /app\n")
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+
+        let err = DockerFile::parse("FROM alpine\nADD --chmod=u+x a /app\n")
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+
+        let err = DockerFile::parse("FROM alpine\nRUN --mount=type=tmpfs,target=/x ls\n")
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+
+        for interval in [
+            "99999999999999999999999h",
+            "10000000000000000000s10000000000000000000s",
+        ] {
+            let text = format!(
+                "FROM alpine\nHEALTHCHECK --interval={} CMD true\n",
+                interval
+            );
+            let err = DockerFile::parse(&text).err().unwrap();
+            assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+        }
+    }
+
+    #[test]
+    fn test_parse_run_flags() {
+        let text = "FROM rust\n\
+            RUN --mount=type=cache,dst=/root/.cargo,sharing=private \\\n\
+            --mount=target=/src,from=builder,rw --mount=type=secret,id=token,required \\\n\
+            --network=host cargo build\n";
+        let df = DockerFile::parse(text).unwrap();
+        assert!(df.needs_buildkit());
+        assert_eq!(
+            df.stages[0].instrs[0].to_string(),
+            "RUN --mount=type=cache,target=/root/.cargo,sharing=private \
+--mount=type=bind,target=/src,from=builder,rw=true \
+--mount=type=secret,id=token,required=true --network=host cargo build"
+        );
+        assert_eq!(
+            DockerFile::parse(&df.to_string()).unwrap().to_string(),
+            df.to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_copy_flags() {
+        let text = "FROM alpine\n\
+            COPY --link --chown=app:app --from=builder --chmod=0755 --parents a b ./out/\n\
+            ADD --link=false --chown=1000 https://example.com/x.tar.gz /opt/\n";
+        let df = DockerFile::parse(text).unwrap();
+        assert_eq!(
+            df.to_string(),
+            "FROM alpine\n\
+            COPY --from=builder --chown=app:app --chmod=0755 --link --parents a b ./out/\n\
+            ADD --chown=1000 https://example.com/x.tar.gz /opt/\n"
+        );
+        assert!(df.needs_buildkit());
+    }
+
+    #[test]
+    fn test_dockerfile_macro() {
+        let df = crate::dockerfile! {
+            FROM --platform=linux/amd64 alpine:3.19 AS base;
+            ENV PATH="/opt/bin:${PATH}";
+            RUN --mount=type=cache,target=/var/cache/apk apk add curl && echo "a  b" > /tmp/x;
+            COPY --chown=app:app ["my file", "/app/"];
+            ENTRYPOINT ["sh", "-c"]
+        };
+        assert_eq!(
+            df.to_string(),
+            r#"FROM --platform=linux/amd64 alpine:3.19 AS base
+ENV PATH=/opt/bin:${PATH}
+RUN --mount=type=cache,target=/var/cache/apk apk add curl \
+    && echo "a  b" > /tmp/x
+COPY --chown=app:app ["my file", "/app/"]
+ENTRYPOINT ["sh", "-c"]
+"#
+        );
+
+        let version = "3.20";
+        let package = String::from("git");
+        let df = crate::dockerfile! {
+            FROM alpine:{version};
+            RUN apk add {package} {format!("{}-doc", package)};
+            CMD ["echo", {package}];
+        }
+        .unwrap();
+        assert_eq!(
+            df.to_string(),
+            "FROM alpine:3.20\nRUN apk add git git-doc\nCMD [\"echo\", \"git\"]\n"
+        );
+
+        let line = line!() as usize;
+        let injected = "hi\nUSER root";
+        let continued = "hi \\";
+        let version = "3 19";
+        let err = crate::dockerfile! { FROM alpine:3.19; RUN echo {injected} }
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+        assert_eq!((err.line, err.column), (line + 4, 58));
+        let err = crate::dockerfile! {
+            FROM alpine:3.19;
+            RUN echo {continued};
+            USER root
+        }
+        .err()
+        .unwrap();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidArgument(_)));
+        assert_eq!((err.line, err.column), (line + 11, 13));
+        let err = crate::dockerfile! { FROM alpine:{version} }.err().unwrap();
+        assert_eq!((err.line, err.column), (line + 18, 40));
+
+        let df = crate::dockerfile! { FROM alpine:3.19; CMD ["echo", {injected}] }.unwrap();
+        assert_eq!(df.instructions().count(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid dockerfile!")]
+    fn test_dockerfile_macro_literal_error() {
+        crate::dockerfile! { FROM alpine:3.19; COPY --chmod=u+x a b };
+    }
+}
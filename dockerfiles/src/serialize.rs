@@ -0,0 +1,346 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt::Formatter,
+    sync::{LazyLock, RwLock},
+};
+
+use serde::{
+    de::{DeserializeOwned, DeserializeSeed, Error as _, IgnoredAny, MapAccess, Visitor},
+    ser::{Error as _, SerializeMap},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::*;
+
+type SerializeFn = fn(&dyn Instruction) -> &dyn erased_serde::Serialize;
+type DeserializeFn =
+    fn(&mut dyn erased_serde::Deserializer) -> Result<Box<dyn Instruction>, erased_serde::Error>;
+
+/// Instructions are (de)serialized as a map from their tag to their fields,
+/// e.g. `{"WORKDIR": {"path": "/app"}}`
+#[derive(Default)]
+struct Registry {
+    tags: HashMap<TypeId, (&'static str, SerializeFn)>,
+    types: HashMap<&'static str, DeserializeFn>,
+}
+
+static REGISTRY: LazyLock<RwLock<Registry>> = LazyLock::new(|| {
+    let mut registry = Registry::default();
+    registry.register::<Copy>("COPY");
+    registry.register::<Volume>("VOLUME");
+    registry.register::<Run>("RUN");
+    registry.register::<EntryPoint>("ENTRYPOINT");
+    registry.register::<Env>("ENV");
+    registry.register::<Arg>("ARG");
+    registry.register::<WorkDir>("WORKDIR");
+    registry.register::<User>("USER");
+    registry.register::<Expose>("EXPOSE");
+    registry.register::<Label>("LABEL");
+    registry.register::<Cmd>("CMD");
+    registry.register::<Add>("ADD");
+    registry.register::<Shell>("SHELL");
+    registry.register::<StopSignal>("STOPSIGNAL");
+    registry.register::<HealthCheck>("HEALTHCHECK");
+    registry.register::<OnBuild>("ONBUILD");
+    RwLock::new(registry)
+});
+
+impl Registry {
+    fn register<T: Instruction + Serialize + DeserializeOwned>(&mut self, tag: &'static str) {
+        // the type registered with `tag` before is no longer serializable
+        self.tags.retain(|_, (x, _)| *x != tag);
+        if let Some((old, _)) = self.tags.insert(TypeId::of::<T>(), (tag, serialize::<T>)) {
+            self.types.remove(old);
+        }
+        self.types.insert(tag, deserialize::<T>);
+    }
+}
+
+/// Make a user-defined instruction (de)serializable as part of a
+/// [DockerFile], under `tag`. Registering a tag again replaces it
+pub fn register_instruction<T>(tag: &'static str)
+where
+    T: Instruction + Serialize + DeserializeOwned,
+{
+    REGISTRY.write().unwrap().register::<T>(tag);
+}
+
+fn serialize<T: Instruction + Serialize>(instr: &dyn Instruction) -> &dyn erased_serde::Serialize {
+    instr
+        .downcast_ref::<T>()
+        .expect("instruction registered with the wrong type")
+}
+
+fn deserialize<T: Instruction + DeserializeOwned>(
+    deserializer: &mut dyn erased_serde::Deserializer,
+) -> Result<Box<dyn Instruction>, erased_serde::Error> {
+    erased_serde::deserialize::<T>(deserializer).map(|x| Box::new(x) as _)
+}
+
+impl Serialize for dyn Instruction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let type_id = Any::type_id(self.as_any());
+        // copied out so nested instructions (ONBUILD) don't lock again
+        let entry = REGISTRY.read().unwrap().tags.get(&type_id).copied();
+        let Some((tag, serialize)) = entry else {
+            return Err(S::Error::custom(format!(
+                "{} instruction is not registered, see register_instruction",
+                self.keyword()
+            )));
+        };
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(tag, serialize(self))?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Box<dyn Instruction> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(InstructionVisitor)
+    }
+}
+
+struct InstructionVisitor;
+
+impl<'de> Visitor<'de> for InstructionVisitor {
+    type Value = Box<dyn Instruction>;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "a map from an instruction tag to its fields")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let tag: String = map
+            .next_key()?
+            .ok_or_else(|| A::Error::custom("missing instruction tag"))?;
+        let deserialize = REGISTRY.read().unwrap().types.get(tag.as_str()).copied();
+        let Some(deserialize) = deserialize else {
+            return Err(A::Error::custom(format!(
+                "unknown instruction {}, see register_instruction",
+                tag
+            )));
+        };
+        let instr = map.next_value_seed(InstructionSeed(deserialize))?;
+        if map.next_key::<IgnoredAny>()?.is_some() {
+            return Err(A::Error::custom("expected a single instruction tag"));
+        }
+        Ok(instr)
+    }
+}
+
+struct InstructionSeed(DeserializeFn);
+
+impl<'de> DeserializeSeed<'de> for InstructionSeed {
+    type Value = Box<dyn Instruction>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let mut erased = <dyn erased_serde::Deserializer>::erase(deserializer);
+        (self.0)(&mut erased).map_err(D::Error::custom)
+    }
+}
+
+/// A [DockerFile] as deserialized, before it is checked
+#[derive(Deserialize)]
+pub(crate) struct RawDockerFile {
+    directives: Vec<(String, String)>,
+    args: Vec<Arg>,
+    stages: Vec<Stage>,
+    #[serde(default)]
+    entry_point: bool,
+}
+
+impl TryFrom<RawDockerFile> for DockerFile {
+    type Error = String;
+
+    fn try_from(raw: RawDockerFile) -> Result<Self, Self::Error> {
+        if raw.stages.is_empty() {
+            return Err("a docker file needs at least one stage".to_string());
+        }
+        Ok(Self {
+            directives: raw.directives,
+            args: raw.args,
+            stages: raw.stages,
+            entry_point: raw.entry_point,
+            locations: Default::default(),
+        })
+    }
+}
+
+/// A [Copy] as deserialized, before it is checked
+#[derive(Deserialize)]
+pub(crate) struct RawCopy {
+    sources: Vec<String>,
+    to: String,
+    stage: Option<String>,
+    chown: Option<String>,
+    chmod: Option<String>,
+    link: bool,
+    parents: bool,
+    heredocs: Vec<Heredoc>,
+}
+
+impl TryFrom<RawCopy> for Copy {
+    type Error = String;
+
+    fn try_from(raw: RawCopy) -> Result<Self, Self::Error> {
+        check_copy(
+            &raw.sources,
+            &raw.to,
+            raw.chown.as_deref(),
+            raw.chmod.as_deref(),
+        )?;
+        Ok(Self {
+            sources: raw.sources,
+            to: raw.to,
+            stage: raw.stage,
+            chown: raw.chown,
+            chmod: raw.chmod,
+            link: raw.link,
+            parents: raw.parents,
+            heredocs: raw.heredocs,
+        })
+    }
+}
+
+/// An [Add] as deserialized, before it is checked
+#[derive(Deserialize)]
+pub(crate) struct RawAdd {
+    sources: Vec<String>,
+    to: String,
+    chown: Option<String>,
+    chmod: Option<String>,
+    link: bool,
+    heredocs: Vec<Heredoc>,
+}
+
+impl TryFrom<RawAdd> for Add {
+    type Error = String;
+
+    fn try_from(raw: RawAdd) -> Result<Self, Self::Error> {
+        check_copy(
+            &raw.sources,
+            &raw.to,
+            raw.chown.as_deref(),
+            raw.chmod.as_deref(),
+        )?;
+        Ok(Self {
+            sources: raw.sources,
+            to: raw.to,
+            chown: raw.chown,
+            chmod: raw.chmod,
+            link: raw.link,
+            heredocs: raw.heredocs,
+        })
+    }
+}
+
+impl Serialize for ImageRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let reference = String::deserialize(deserializer)?;
+        ImageRef::parse(&reference).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    fn docker_file() -> DockerFile {
+        DockerFile::new(From::image("rust").with_tag("1.80").with_alias("builder"))
+            .with_directive("syntax", "docker/dockerfile:1")
+            .global_arg(Arg::new("VERSION").with_default("1.0"))
+            .then(WorkDir::new("/src"))
+            .then(
+                Run::new("cargo build --release")
+                    .with_mount(Mount::cache("/usr/local/cargo/registry"))
+                    .with_network(Network::None),
+            )
+            .then(Run::new("<<EOF").with_heredoc("EOF", "set -e\necho done"))
+            .then(OnBuild::new(Copy::new(".", "/src/")))
+            .stage(From::image("alpine").with_tag("3.19"))
+            .then(Copy::new("/src/app", "/app").from_stage("builder"))
+            .then(HealthCheck::cmd(Form::exec(["/app", "health"])).with_retries(3))
+            .then(Expose::udp(53))
+            .entry_point(["/app"])
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let df = docker_file();
+        let json = serde_json::to_string(&df).unwrap();
+        let back: DockerFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_string(), df.to_string());
+        assert!(json.contains(r#"{"WORKDIR":{"path":"/src"}}"#), "{}", json);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let df = docker_file();
+        let toml = toml::to_string(&df).unwrap();
+        let back: DockerFile = toml::from_str(&toml).unwrap();
+        assert_eq!(back.to_string(), df.to_string());
+    }
+
+    #[test]
+    fn test_register_instruction() {
+        #[derive(Instruction, serde::Serialize, serde::Deserialize)]
+        #[instruction(keyword = "MAINTAINER")]
+        struct Maintainer {
+            name: String,
+        }
+
+        let df = DockerFile::new(From::image("alpine")).then(Maintainer {
+            name: "me".to_string(),
+        });
+        assert!(serde_json::to_string(&df).is_err());
+        let json = r#"{"FROM": "alpine"}"#;
+        assert!(serde_json::from_str::<Box<dyn Instruction>>(json).is_err());
+
+        register_instruction::<Maintainer>("MAINTAINER");
+        let json = serde_json::to_string(&df).unwrap();
+        let back: DockerFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_string(), "FROM alpine\nMAINTAINER me\n");
+
+        // the tag now names another type, Maintainer isn't serializable
+        #[derive(Instruction, serde::Serialize, serde::Deserialize)]
+        #[instruction(keyword = "MAINTAINER")]
+        struct Author {
+            name: String,
+        }
+        register_instruction::<Author>("MAINTAINER");
+        assert!(serde_json::to_string(&df).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_checks() {
+        let err = serde_json::from_str::<DockerFile>(r#"{"directives":[],"args":[],"stages":[]}"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("at least one stage"), "{}", err);
+
+        let copy = |sources: &str, chmod: &str| {
+            let json = format!(
+                r#"{{"sources":{},"to":"/app","stage":null,"chown":null,"chmod":{},"link":false,"parents":false,"heredocs":[]}}"#,
+                sources, chmod
+            );
+            serde_json::from_str::<Copy>(&json).map(|x| x.to_string())
+        };
+        assert_eq!(
+            copy(r#"["a"]"#, r#""0755""#).unwrap(),
+            "COPY --chmod=0755 a /app"
+        );
+        assert!(copy("[]", "null").is_err());
+        assert!(copy(r#"["a", "b"]"#, "null").is_err());
+        assert!(copy(r#"["a"]"#, r#""u+x""#).is_err());
+
+        let add = r#"{"sources":["a"],"to":"/app","chown":"a b","chmod":null,"link":false,"heredocs":[]}"#;
+        assert!(serde_json::from_str::<Add>(add).is_err());
+    }
+}